@@ -0,0 +1,204 @@
+// Generated by `deka-supremecourt-rs --write-schema schema`. Do not edit.
+// Deka worker protocol v1
+
+export interface DekaInfo {
+  deka_no: string;
+  long_note?: string | null;
+  metadata: DekaMetadata;
+  short_note: string;
+}
+
+export interface DekaMetadata {
+  law: string;
+  source: string;
+  /** Page the deka was read from, when it has one of its own */
+  url?: string | null;
+}
+
+/** Field names for showing a `DekaInfo`. */
+export interface Labels {
+  deka_no: string;
+  law: string;
+  long_note: string;
+  short_note: string;
+  source: string;
+}
+
+export interface MessagePayload {
+  info: TGDeka;
+  /** Correlates log lines and the response with this request */
+  job_id?: string | null;
+  message: TGMessgae;
+  /** Also send the result as Telegram messages written for this mode */
+  parse_mode?: ParseMode | null;
+  /** Version the bot wrote this request for, `1` when missing */
+  protocol_version?: number | null;
+  /** Ask every source and send `Partial` results as they come in */
+  stream?: boolean;
+}
+
+/** Telegram `parse_mode` the parts are written for. */
+export type ParseMode = "MarkdownV2" | "HTML";
+
+/** Sent by the bot to stop a request, found by `job_id` or `update_id`. */
+export interface TGCancel {
+  job_id?: string | null;
+  update_id?: number | null;
+}
+
+/** Bot API `Chat`. Group and channel IDs are negative. */
+export interface TGChat {
+  first_name?: string | null;
+  id: number;
+  is_forum?: boolean | null;
+  last_name?: string | null;
+  title?: string | null;
+  type: TGChatType;
+  username?: string | null;
+  [key: string]: unknown;
+}
+
+export type TGChatType = "private" | "group" | "supergroup" | "channel" | "unknown";
+
+export type TGDeka = TGDekaNumber & { mode: "number"; } | TGDekaSearch & { mode: "search"; };
+
+export interface TGDekaNumber {
+  dekaSerial: string;
+  dekaYear: number;
+  withLongNote: boolean;
+}
+
+export interface TGDekaSearch {
+  caseFrom?: number | null;
+  caseTo?: number | null;
+  searchLaw?: string | null;
+  searchLawNo?: string | null;
+  searchWords: Array<string>;
+  withLongNote: boolean;
+}
+
+/** Stable error taxonomy the bot can act on. */
+export type TGErrorCode = "not_found" | "timeout" | "rate_limited" | "internal" | "source_unavailable" | "site_layout_changed" | "invalid_query";
+
+/** Machine-readable reason a request was refused. */
+export type TGErrorReason = "unsupported_mode" | "invalid_request" | "unsupported_version";
+
+/** What this worker can do, sent once each time the WebSocket connects. */
+export interface TGHello {
+  /** Lookups run at the same time */
+  max_concurrency: number;
+  /** `TGDeka` modes accepted */
+  modes: Array<string>;
+  protocol_version: number;
+  /** Sources tried, in order */
+  sources: Array<string>;
+  worker_id: string;
+  worker_version: string;
+}
+
+/** Sent while a request is being worked on. The final `Okay` or `Err` follows with the same `job_id`, unless the job is cancelled. */
+export type TGJobStatus = { from: string; job_id: string; message: TGMessgae; text?: string; } & ({ queue_position: number; state: "accepted"; } | { source: string; state: "running"; } | { state: "progress"; step: TGJobStep; } | { state: "cancelled"; superseded_by?: string | null; });
+
+/** A step inside a source, for "opening print page" style updates. */
+export type TGJobStep = "fetching_long_notes" | "opening_print_page" | "searching";
+
+/** Bot API `MessageEntity`. Offsets and lengths are in UTF-16 code units. */
+export interface TGMessageEntity {
+  language?: string | null;
+  length: number;
+  offset: number;
+  type: string;
+  url?: string | null;
+  user?: TGUser | null;
+  [key: string]: unknown;
+}
+
+/** Bot API `Message` */
+export interface TGMessageInfo {
+  caption?: string | null;
+  caption_entities?: Array<TGMessageEntity> | null;
+  chat: TGChat;
+  date: number;
+  edit_date?: number | null;
+  entities?: Array<TGMessageEntity> | null;
+  /** Empty for channel posts */
+  from?: TGUser | null;
+  is_topic_message?: boolean | null;
+  message_id: number;
+  /** Forum topic the message belongs to */
+  message_thread_id?: number | null;
+  reply_to_message?: TGMessageInfo | null;
+  /** Sent on behalf of a chat, e.g. a channel or an anonymous admin */
+  sender_chat?: TGChat | null;
+  text?: string | null;
+  [key: string]: unknown;
+}
+
+/** Bot API `Update`. At most one of the message fields is set. */
+export interface TGMessgae {
+  channel_post?: TGMessageInfo | null;
+  edited_channel_post?: TGMessageInfo | null;
+  edited_message?: TGMessageInfo | null;
+  message?: TGMessageInfo | null;
+  update_id: number;
+  [key: string]: unknown;
+}
+
+/** Anything the bot sends */
+export type TGRequest = MessagePayload | { cancel: TGCancel; };
+
+export type TGResponse = { Okay: TGResponseOkay; } | { Err: TGResponseErr; } | { Hello: TGHello; } | { Status: TGJobStatus; } | { Partial: TGResponsePartial; };
+
+export interface TGResponseErr {
+  code?: TGErrorCode;
+  /** For logs only, may name internal URLs and errors */
+  details?: string | null;
+  /** Safe to show to the user, in their language */
+  error: string;
+  from: string;
+  job_id?: string | null;
+  message: TGMessgae;
+  reason?: TGErrorReason | null;
+  retryable?: boolean;
+}
+
+export interface TGResponseOkay {
+  from: string;
+  job_id?: string | null;
+  labels?: Labels | null;
+  message: TGMessgae;
+  /** Set when the request asked for `parts` */
+  parse_mode?: ParseMode | null;
+  /** `result`, or `text` when there is none, as messages ready to send */
+  parts?: Array<string>;
+  result?: Array<DekaInfo> | null;
+  /** What to tell the user when `result` is empty */
+  text?: string | null;
+}
+
+/** Results so far for a request sent with `stream`. Each one holds all that was found yet, so the bot can redraw its message from the latest. The final `Okay` follows with the same `job_id`. */
+export interface TGResponsePartial {
+  from: string;
+  job_id: string;
+  labels?: Labels | null;
+  message: TGMessgae;
+  parse_mode?: ParseMode | null;
+  /** `result` as messages ready to send, when the request asked for them */
+  parts?: Array<string>;
+  result: Array<DekaInfo>;
+  /** Counts up from 1 within a job */
+  seq: number;
+  /** Source that added to the result this time */
+  source: string;
+}
+
+/** Bot API `User` */
+export interface TGUser {
+  first_name: string;
+  id: number;
+  is_bot: boolean;
+  language_code?: string | null;
+  last_name?: string | null;
+  username?: string | null;
+  [key: string]: unknown;
+}
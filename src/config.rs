@@ -0,0 +1,628 @@
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use toml::{Table, Value};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+use crate::{
+    error,
+    logging::LogConfig,
+    service::{
+        bot::{Backoff, Heartbeat},
+        browser::BrowserConfig,
+        deka::DekaConfig,
+        http::HttpConfig,
+    },
+    util,
+};
+
+/// Used when neither `--config` nor `DEKA_CONFIG` is given and the file exists.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const DOTENV_PATH: &str = ".env";
+/// `DEKA__BROWSER__POOL_SIZE=3` sets `browser.pool_size`.
+const ENV_PREFIX: &str = "DEKA__";
+/// Short names kept for `sample.env` and older deployments. Matched case-insensitively.
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("WS_ADDR", "bot.ws_addr"),
+    ("TOKEN", "bot.token"),
+    ("LOG_LEVEL", "log.level"),
+    ("WEBDRIVER_URL", "browser.webdriver_url"),
+    ("WEBDRIVER_PATH", "browser.webdriver_path"),
+    ("SPOOL_PATH", "spool.path"),
+];
+
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Telegram bot worker: Scrapper")]
+pub struct Cli {
+    /// TOML config file
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Print the resolved configuration, with secrets masked, and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Write the protocol JSON Schema and TypeScript types into DIR and exit
+    #[arg(long, value_name = "DIR")]
+    pub write_schema: Option<PathBuf>,
+    /// Override any key, e.g. `--set browser.pool_size=3`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// Same as `--set bot.ws_addr=...`
+    #[arg(long)]
+    pub ws_addr: Option<String>,
+    /// Same as `--set log.level=...`
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Same as `--set browser.webdriver_url=...`
+    #[arg(long)]
+    pub webdriver_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    pub ws_addr: String,
+    pub token: String,
+    pub reconnect_base_ms: u64,
+    pub reconnect_max_secs: u64,
+    pub reconnect_max_retries: u32,
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+    /// Sent to the bot in the hello, `<hostname>-<pid>` when unset
+    pub worker_id: Option<String>,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            ws_addr: String::new(),
+            token: String::new(),
+            reconnect_base_ms: 1000,
+            reconnect_max_secs: 60,
+            reconnect_max_retries: 30,
+            heartbeat_interval_secs: 20,
+            heartbeat_timeout_secs: 10,
+            worker_id: None,
+        }
+    }
+}
+
+impl BotConfig {
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.reconnect_base_ms),
+            Duration::from_secs(self.reconnect_max_secs),
+            self.reconnect_max_retries,
+        )
+    }
+
+    pub fn worker_id(&self) -> String {
+        self.worker_id.clone().unwrap_or_else(|| {
+            let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|h| h.trim().to_string())
+                .unwrap_or_else(|_| "deka".to_string());
+
+            format!("{}-{}", host, std::process::id())
+        })
+    }
+
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat::new(
+            Duration::from_secs(self.heartbeat_interval_secs),
+            Duration::from_secs(self.heartbeat_timeout_secs),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// Mirror the spool to this JSONL file
+    pub path: Option<PathBuf>,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    /// Append requests that could not be parsed to this JSONL file
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            capacity: 500,
+            ttl_secs: 15 * 60,
+            dead_letter: None,
+        }
+    }
+}
+
+/// What a reload changed, and how it has to be applied.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Changes {
+    /// Applied live
+    pub log_level: bool,
+    /// Applied live to new lookups
+    pub deka: bool,
+    /// Applied by reconnecting to the bot
+    pub bot: bool,
+    /// Keys that only take effect after a restart
+    pub restart: Vec<&'static str>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bot: BotConfig,
+    pub log: LogConfig,
+    pub deka: DekaConfig,
+    pub browser: BrowserConfig,
+    pub spool: SpoolConfig,
+    pub http: HttpConfig,
+}
+
+fn invalid<T>(key: &str, message: impl Into<String>) -> util::Result<T> {
+    error::ConfigSnafu {
+        key,
+        message: message.into(),
+    }
+    .fail()
+}
+
+fn merge(base: &mut Table, over: Table) {
+    for (k, v) in over {
+        match (base.get_mut(&k), v) {
+            (Some(Value::Table(base)), Value::Table(over)) => merge(base, over),
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+/// Read `raw` as the type `current` has. Options left unset have no type,
+/// so they stay strings unless `raw` is an inline table or array.
+fn coerce(current: Option<&Value>, raw: &str) -> Value {
+    let toml = || {
+        toml::from_str::<Table>(&format!("v = {}", raw))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+    };
+    let value = match current {
+        Some(Value::String(_)) => None,
+        Some(Value::Integer(_)) => raw.trim().parse().ok().map(Value::Integer),
+        Some(Value::Float(_)) => raw.trim().parse().ok().map(Value::Float),
+        Some(Value::Boolean(_)) => raw.trim().parse().ok().map(Value::Boolean),
+        Some(_) => toml(),
+        None => toml().filter(|v| v.is_table() || v.is_array()),
+    };
+
+    // A value of the wrong type is left to deserializing to report
+    value.unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// Set `section.key` from a raw string, read as the type the key has in
+/// `table`.
+fn set(table: &mut Table, key: &str, raw: &str) -> util::Result<()> {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let Some(leaf) = parts.pop().filter(|l| !l.is_empty()) else {
+        return invalid(key, "empty key");
+    };
+
+    let mut node = table;
+    for part in parts {
+        node = match node
+            .entry(part)
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(t) => t,
+            _ => return invalid(key, format!("`{}` is not a section", part)),
+        };
+    }
+
+    let value = coerce(node.get(leaf), raw);
+    node.insert(leaf.to_string(), value);
+
+    Ok(())
+}
+
+fn env_key(name: &str) -> Option<String> {
+    if let Some((_, key)) = ENV_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    {
+        return Some(key.to_string());
+    }
+
+    name.strip_prefix(ENV_PREFIX).map(|rest| {
+        rest.split("__")
+            .collect::<Vec<_>>()
+            .join(".")
+            .to_lowercase()
+    })
+}
+
+/// The process environment. Variables this worker reads must be UTF-8,
+/// the others are skipped.
+fn env_vars() -> util::Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+
+    for (name, raw) in env::vars_os() {
+        match (name.into_string(), raw.into_string()) {
+            (Ok(name), Ok(raw)) => vars.push((name, raw)),
+            (Ok(name), Err(_)) if name == "DEKA_CONFIG" || env_key(&name).is_some() => {
+                return invalid(&name, "is not UTF-8");
+            }
+            _ => {}
+        }
+    }
+
+    Ok(vars)
+}
+
+impl Config {
+    /// Defaults, then the TOML file, then `.env`, then the environment, then CLI flags.
+    pub fn load(cli: &Cli) -> util::Result<Self> {
+        let dotenv = Self::read_dotenv(Path::new(DOTENV_PATH))?;
+
+        Self::load_from(cli, env_vars()?, dotenv)
+    }
+
+    fn load_from(
+        cli: &Cli,
+        vars: impl IntoIterator<Item = (String, String)>,
+        dotenv: impl IntoIterator<Item = (String, String)>,
+    ) -> util::Result<Self> {
+        // `.env` is read on every load and never copied into the process
+        // environment, so edits to it apply on reload. Real variables win.
+        let vars = dotenv.into_iter().chain(vars).collect::<Vec<_>>();
+        let mut table = Table::try_from(Self::default()).context(error::TomlSerSnafu)?;
+
+        let path = cli.config.clone().or_else(|| {
+            vars.iter()
+                .rfind(|(k, _)| k == "DEKA_CONFIG")
+                .map(|(_, v)| PathBuf::from(v))
+        });
+        match path {
+            Some(path) => merge(&mut table, Self::read_file(&path)?),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                merge(&mut table, Self::read_file(Path::new(DEFAULT_CONFIG_PATH))?)
+            }
+            None => {}
+        }
+
+        for (name, raw) in vars.iter() {
+            if let Some(key) = env_key(name) {
+                set(&mut table, &key, raw)?;
+            }
+        }
+
+        let shortcuts = [
+            ("bot.ws_addr", &cli.ws_addr),
+            ("log.level", &cli.log_level),
+            ("browser.webdriver_url", &cli.webdriver_url),
+        ];
+        for (key, raw) in shortcuts {
+            if let Some(raw) = raw {
+                set(&mut table, key, raw)?;
+            }
+        }
+        for kv in cli.overrides.iter() {
+            match kv.split_once('=') {
+                Some((key, raw)) => set(&mut table, key.trim(), raw)?,
+                None => return invalid(kv, "expected KEY=VALUE"),
+            }
+        }
+
+        serde_path_to_error::deserialize(Value::Table(table)).or_else(|e| {
+            let key = e.path().to_string();
+            invalid(&key, e.into_inner().message())
+        })
+    }
+
+    /// Entries of the `.env` file, none if there is no such file.
+    fn read_dotenv(path: &Path) -> util::Result<Vec<(String, String)>> {
+        match dotenvy::from_path_iter(path) {
+            Ok(iter) => iter
+                .collect::<Result<_, _>>()
+                .context(error::DotenvSnafu { path }),
+            Err(e) if e.not_found() => Ok(Vec::new()),
+            Err(e) => Err(e).context(error::DotenvSnafu { path }),
+        }
+    }
+
+    fn read_file(path: &Path) -> util::Result<Table> {
+        let txt = std::fs::read_to_string(path).context(error::ConfigFileSnafu { path })?;
+
+        toml::from_str(&txt).context(error::TomlSnafu { path })
+    }
+
+    pub fn validate(&self) -> util::Result<()> {
+        if self.bot.ws_addr.trim().is_empty() {
+            return invalid("bot.ws_addr", "must be set");
+        }
+        if self.bot.token.trim().is_empty() {
+            return invalid("bot.token", "must be set");
+        }
+        if self.bot.heartbeat_interval_secs == 0 {
+            return invalid("bot.heartbeat_interval_secs", "must be greater than 0");
+        }
+        if self.bot.heartbeat_timeout_secs == 0 {
+            return invalid("bot.heartbeat_timeout_secs", "must be greater than 0");
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            return invalid("log.level", e.to_string());
+        }
+        if self.deka.max_concurrency == 0 {
+            return invalid("deka.max_concurrency", "must be greater than 0");
+        }
+        if self.deka.sources.is_empty() {
+            return invalid("deka.sources", "must list at least one source");
+        }
+        if self.deka.sources.iter().unique().count() != self.deka.sources.len() {
+            return invalid("deka.sources", "lists a source twice");
+        }
+        if self.browser.pool_size == 0 {
+            return invalid("browser.pool_size", "must be greater than 0");
+        }
+        if self.http.enabled && self.http.listen.parse::<SocketAddr>().is_err() {
+            return invalid(
+                "http.listen",
+                format!("{:?} is not an address", self.http.listen),
+            );
+        }
+        if self.http.liveness_timeout_secs == 0 {
+            return invalid("http.liveness_timeout_secs", "must be greater than 0");
+        }
+
+        for (key, url) in [
+            ("deka.dekasuksa_url", &self.deka.dekasuksa_url),
+            ("deka.spc_url", &self.deka.spc_url),
+            ("browser.webdriver_url", &self.browser.webdriver_url),
+        ] {
+            if let Err(e) = Url::parse(url) {
+                return invalid(key, format!("{:?} is not a URL: {}", url, e));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare against a freshly loaded config.
+    pub fn changes(&self, new: &Config) -> Changes {
+        let mut restart = Vec::new();
+
+        if self.log.format != new.log.format
+            || self.log.file != new.log.file
+            || self.log.rotation != new.log.rotation
+            || self.log.max_files != new.log.max_files
+        {
+            restart.push("log");
+        }
+        if self.deka.max_concurrency != new.deka.max_concurrency {
+            restart.push("deka.max_concurrency");
+        }
+        if self.browser != new.browser {
+            restart.push("browser");
+        }
+        if self.spool != new.spool {
+            restart.push("spool");
+        }
+        if self.http != new.http {
+            restart.push("http");
+        }
+
+        Changes {
+            log_level: self.log.level != new.log.level,
+            deka: self.deka != new.deka,
+            bot: self.bot != new.bot,
+            restart,
+        }
+    }
+
+    /// The config as TOML with the bot token masked.
+    pub fn to_toml_redacted(&self) -> util::Result<String> {
+        let mut config = self.clone();
+
+        if !config.bot.token.is_empty() {
+            config.bot.token = "********".to_string();
+        }
+
+        toml::to_string_pretty(&config).context(error::TomlSerSnafu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service::browser::WindowSize;
+
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layers_override_in_order() {
+        let path = env::temp_dir().join(format!("config-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[bot]\nws_addr = \"file.example.com\"\ntoken = \"file\"\n\n[browser]\npool_size = 5\nheadless = false\n",
+        )
+        .unwrap();
+
+        let cli = Cli {
+            config: Some(path.clone()),
+            ws_addr: Some("cli.example.com".to_string()),
+            overrides: vec!["deka.max_concurrency=8".to_string()],
+            ..Default::default()
+        };
+        let config = Config::load_from(
+            &cli,
+            vars(&[
+                ("WS_ADDR", "env.example.com"),
+                ("token", "12345"),
+                ("DEKA__BROWSER__POOL_SIZE", "3"),
+                ("UNRELATED", "x"),
+            ]),
+            [],
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.bot.ws_addr, "cli.example.com");
+        // Numeric-looking secrets stay strings
+        assert_eq!(config.bot.token, "12345");
+        assert_eq!(config.browser.pool_size, 3);
+        assert!(!config.browser.headless);
+        assert_eq!(config.deka.max_concurrency, 8);
+        assert_eq!(config.deka.spc_url, DekaConfig::default().spc_url);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn env_goes_over_dotenv() {
+        let path = env::temp_dir().join(format!("config-test-{}.env", std::process::id()));
+        let load = || {
+            let dotenv = Config::read_dotenv(&path).unwrap();
+            Config::load_from(&Cli::default(), vars(&[("TOKEN", "env")]), dotenv).unwrap()
+        };
+
+        std::fs::write(&path, "TOKEN=file\nWS_ADDR=file.example.com\n").unwrap();
+        let config = load();
+        assert_eq!(config.bot.token, "env");
+        assert_eq!(config.bot.ws_addr, "file.example.com");
+        // Edits show up on the next load
+        std::fs::write(&path, "TOKEN=file\nWS_ADDR=edited.example.com\n").unwrap();
+        let config = load();
+        assert_eq!(config.bot.token, "env");
+        assert_eq!(config.bot.ws_addr, "edited.example.com");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_value_names_key() {
+        let err = Config::load_from(
+            &Cli::default(),
+            vars(&[("DEKA__BROWSER__POOL_SIZE", "many")]),
+            [],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&err, error::Error::ConfigError { key, .. } if key == "browser.pool_size"),
+            "{:?}",
+            err
+        );
+
+        let err =
+            Config::load_from(&Cli::default(), vars(&[("DEKA__BOT__TYPO", "1")]), []).unwrap_err();
+        assert!(
+            matches!(&err, error::Error::ConfigError { .. }),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("typo"), "{}", err);
+    }
+
+    #[test]
+    fn values_take_the_field_type() {
+        let cli = Cli {
+            overrides: vec![
+                "bot.worker_id=42".to_string(),
+                "browser.window_size={ width = 800, height = 600 }".to_string(),
+                "browser.headless=false".to_string(),
+            ],
+            ..Default::default()
+        };
+        let config = Config::load_from(
+            &cli,
+            vars(&[
+                ("DEKA__BOT__TOKEN", "123456"),
+                ("DEKA__BROWSER__USER_AGENT", "true"),
+                ("DEKA__DEKA__MAX_CONCURRENCY", " 8 "),
+            ]),
+            [],
+        )
+        .unwrap();
+
+        assert_eq!(config.bot.worker_id.as_deref(), Some("42"));
+        assert_eq!(config.bot.token, "123456");
+        assert_eq!(config.browser.user_agent.as_deref(), Some("true"));
+        assert_eq!(
+            config.browser.window_size,
+            Some(WindowSize {
+                width: 800,
+                height: 600
+            })
+        );
+        assert!(!config.browser.headless);
+        assert_eq!(config.deka.max_concurrency, 8);
+    }
+
+    #[test]
+    fn validate_names_key() {
+        let mut config = Config::default();
+        config.bot.ws_addr = "chatbotapi.example.com".to_string();
+        config.bot.token = "secret".to_string();
+        config.validate().unwrap();
+
+        config.bot.heartbeat_timeout_secs = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(error::Error::ConfigError { key, .. }) if key == "bot.heartbeat_timeout_secs"
+        ));
+
+        config.bot.heartbeat_timeout_secs = 10;
+        config.browser.webdriver_url = "localhost 4444".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(error::Error::ConfigError { key, .. }) if key == "browser.webdriver_url"
+        ));
+
+        config.bot.token.clear();
+        assert!(matches!(
+            config.validate(),
+            Err(error::Error::ConfigError { key, .. }) if key == "bot.token"
+        ));
+    }
+
+    #[test]
+    fn changes_split_live_and_restart() {
+        let old = Config::default();
+        let mut new = old.clone();
+        assert_eq!(old.changes(&new), Changes::default());
+
+        new.log.level = "warn".to_string();
+        new.deka.page_timeout_secs = 60;
+        new.bot.token = "rotated".to_string();
+        new.browser.pool_size = 4;
+        let changes = old.changes(&new);
+
+        assert!(changes.log_level && changes.deka && changes.bot);
+        assert_eq!(changes.restart, vec!["browser"]);
+    }
+
+    #[test]
+    fn print_masks_token() {
+        let mut config = Config::default();
+        config.bot.token = "secret".to_string();
+        let txt = config.to_toml_redacted().unwrap();
+
+        assert!(!txt.contains("secret"));
+        assert!(txt.contains("[browser]"));
+        assert_eq!(
+            toml::from_str::<Config>(&txt).unwrap().browser,
+            config.browser
+        );
+    }
+}
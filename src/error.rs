@@ -1,5 +1,8 @@
+use fantoccini::error::CmdError;
 use snafu::{prelude::*, Report};
 
+use crate::model::TGErrorCode;
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
 pub enum Error {
@@ -9,10 +12,39 @@ pub enum Error {
     LevelFilterError {
         source: tracing::metadata::ParseLevelFilterError,
     },
-    #[snafu(display("unsupport env"))]
-    UnsupportEnv {
-        source: std::env::VarError
+    #[snafu(display("invalid log filter"))]
+    LogFilterError {
+        source: tracing_subscriber::filter::ParseError,
+    },
+    #[snafu(display("unable to reload log filter"))]
+    LogReloadError {
+        source: tracing_subscriber::reload::Error,
+    },
+    #[snafu(display("unable to open log file"))]
+    LogFileError {
+        source: tracing_appender::rolling::InitError,
+    },
+    #[snafu(display("invalid config `{key}`: {message}"))]
+    ConfigError { key: String, message: String },
+    #[snafu(display("unable to read config {}", path.display()))]
+    ConfigFileError {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("invalid config {}: {source}", path.display()))]
+    TomlError {
+        path: std::path::PathBuf,
+        source: toml::de::Error,
     },
+    #[snafu(display("invalid env file {}", path.display()))]
+    DotenvError {
+        path: std::path::PathBuf,
+        source: dotenvy::Error,
+    },
+    #[snafu(display("config serialize error"))]
+    TomlSerError { source: toml::ser::Error },
+    #[snafu(display("unsupport env"))]
+    UnsupportEnv { source: std::env::VarError },
     #[snafu(display("overflow error"))]
     Overflow,
     #[snafu(display("Str UTF-8 decode error"))]
@@ -37,11 +69,13 @@ pub enum Error {
     SignalError { source: std::io::Error },
     #[snafu(display("WebSocket error"))]
     WSError {
-        source: async_tungstenite::tungstenite::Error,
+        #[snafu(source(from(async_tungstenite::tungstenite::Error, Box::new)))]
+        source: Box<async_tungstenite::tungstenite::Error>,
     },
     #[snafu(display("Message send error"))]
     SendError {
-        source: tokio::sync::mpsc::error::SendError<crate::model::MessagePayload>,
+        #[snafu(source(from(tokio::sync::mpsc::error::SendError<crate::model::MessagePayload>, Box::new)))]
+        source: Box<tokio::sync::mpsc::error::SendError<crate::model::MessagePayload>>,
     },
     #[snafu(display("HTTP error"))]
     HTTPError { source: http::Error },
@@ -59,6 +93,16 @@ pub enum Error {
     FantocciniSessionError {
         source: fantoccini::error::NewSessionError,
     },
+    #[snafu(display("unknown browser {kind:?}"))]
+    BrowserKindError { kind: String },
+    #[snafu(display("invalid window size {size:?}, expected WIDTHxHEIGHT"))]
+    WindowSizeError { size: String },
+    #[snafu(display("WebDriver not ready in time"))]
+    WebDriverReadyError,
+    #[snafu(display("Browser pool closed"))]
+    BrowserPoolError { source: tokio::sync::AcquireError },
+    #[snafu(display("Browser session has no window left"))]
+    BrowserNoWindowError,
     #[snafu(display("IO error"))]
     IOError { source: std::io::Error },
     #[snafu(display("System Time error"))]
@@ -66,9 +110,94 @@ pub enum Error {
 }
 
 impl Error {
-    pub fn report(&self) -> () {
+    /// Whether the WebDriver session behind this error is gone for good.
+    pub fn is_dead_session(&self) -> bool {
+        match self {
+            Error::FantocciniCmdError { source } => match source {
+                CmdError::Standard(e) => e.error() == "invalid session id",
+                CmdError::NoSuchWindow(_) | CmdError::Lost(_) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// What the bot is told went wrong.
+    pub fn code(&self) -> TGErrorCode {
         match self {
-            e => tracing::error!("error: error_msg {}", Report::from_error(e)),
+            Error::FantocciniCmdError { source } => match source {
+                CmdError::WaitTimeout => TGErrorCode::Timeout,
+                CmdError::Standard(e) if matches!(e.error(), "timeout" | "script timeout") => {
+                    TGErrorCode::Timeout
+                }
+                CmdError::NoSuchElement(_) | CmdError::NoSuchAlert(_) => {
+                    TGErrorCode::SiteLayoutChanged
+                }
+                CmdError::Standard(e)
+                    if matches!(e.error(), "element not interactable" | "invalid selector") =>
+                {
+                    TGErrorCode::SiteLayoutChanged
+                }
+                CmdError::Standard(_)
+                | CmdError::NoSuchWindow(_)
+                | CmdError::Failed(_)
+                | CmdError::Lost(_)
+                | CmdError::NotJson(_)
+                | CmdError::Json(_)
+                | CmdError::NotW3C(_) => TGErrorCode::SourceUnavailable,
+                _ => TGErrorCode::Internal,
+            },
+            Error::ReqwestError { source } if source.is_timeout() => TGErrorCode::Timeout,
+            Error::ReqwestError { source } => match source.status().map(|s| s.as_u16()) {
+                Some(429) => TGErrorCode::RateLimited,
+                Some(404) => TGErrorCode::NotFound,
+                _ => TGErrorCode::SourceUnavailable,
+            },
+            Error::EmptyError => TGErrorCode::SiteLayoutChanged,
+            Error::FantocciniSessionError { .. }
+            | Error::WebDriverReadyError
+            | Error::BrowserPoolError { .. }
+            | Error::BrowserNoWindowError => TGErrorCode::SourceUnavailable,
+            _ => TGErrorCode::Internal,
+        }
+    }
+
+    pub fn report(&self) {
+        tracing::error!("error: error_msg {}", Report::from_error(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(source: CmdError) -> Error {
+        Error::FantocciniCmdError { source }
+    }
+
+    #[test]
+    fn error_codes() {
+        let lost = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let cases = [
+            (cmd(CmdError::WaitTimeout), TGErrorCode::Timeout),
+            (cmd(CmdError::Lost(lost)), TGErrorCode::SourceUnavailable),
+            (
+                cmd(CmdError::NotJson(String::new())),
+                TGErrorCode::SourceUnavailable,
+            ),
+            (
+                cmd(CmdError::InvalidArgument(String::new(), String::new())),
+                TGErrorCode::Internal,
+            ),
+            (Error::EmptyError, TGErrorCode::SiteLayoutChanged),
+            (Error::BrowserNoWindowError, TGErrorCode::SourceUnavailable),
+            (Error::Overflow, TGErrorCode::Internal),
+        ];
+
+        for (e, code) in cases {
+            assert_eq!(e.code(), code, "{:?}", e);
         }
+        assert!(TGErrorCode::Timeout.retryable());
+        assert!(!TGErrorCode::SiteLayoutChanged.retryable());
     }
 }
@@ -0,0 +1,194 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{TGErrorCode, TGJobState, TGJobStep};
+
+/// Language of the texts sent along for the bot to show as they are.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    Th,
+    En,
+}
+
+impl Lang {
+    /// From a Telegram `language_code` such as `th` or `en-US`. Thai when
+    /// the user has none, English for a language without a catalog.
+    pub fn from_code(code: Option<&str>) -> Self {
+        let Some(code) = code.filter(|c| !c.is_empty()) else {
+            return Lang::default();
+        };
+        let code = code.split(['-', '_']).next().unwrap_or_default();
+
+        if code.eq_ignore_ascii_case("th") {
+            Lang::Th
+        } else {
+            Lang::En
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lang::Th => "th",
+            Lang::En => "en",
+        }
+    }
+}
+
+/// Field names for showing a `DekaInfo`.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct Labels {
+    pub deka_no: String,
+    pub short_note: String,
+    pub long_note: String,
+    pub law: String,
+    pub source: String,
+}
+
+impl Labels {
+    pub fn new(lang: Lang) -> Self {
+        let (deka_no, short_note, long_note, law, source) = match lang {
+            Lang::Th => ("ฎีกาที่", "ย่อสั้น", "ย่อยาว", "กฎหมายที่เกี่ยวข้อง", "แหล่งที่มา"),
+            Lang::En => ("Deka No.", "Summary", "Full text", "Law", "Source"),
+        };
+
+        Self {
+            deka_no: deka_no.to_string(),
+            short_note: short_note.to_string(),
+            long_note: long_note.to_string(),
+            law: law.to_string(),
+            source: source.to_string(),
+        }
+    }
+}
+
+pub fn error(lang: Lang, code: TGErrorCode) -> &'static str {
+    match (lang, code) {
+        (Lang::Th, TGErrorCode::NotFound) => "ไม่พบฎีกาที่ตรงกัน",
+        (Lang::Th, TGErrorCode::SourceUnavailable) => {
+            "ไม่สามารถเชื่อมต่อแหล่งข้อมูลฎีกาได้ในขณะนี้ กรุณาลองใหม่ภายหลัง"
+        }
+        (Lang::Th, TGErrorCode::Timeout) => "แหล่งข้อมูลฎีกาตอบช้าเกินไป กรุณาลองใหม่อีกครั้ง",
+        (Lang::Th, TGErrorCode::SiteLayoutChanged) => {
+            "แหล่งข้อมูลฎีกาเปลี่ยนรูปแบบหน้าเว็บ จึงยังอ่านข้อมูลไม่ได้ในขณะนี้"
+        }
+        (Lang::Th, TGErrorCode::InvalidQuery) => "ไม่เข้าใจคำขอนี้",
+        (Lang::Th, TGErrorCode::RateLimited) => "มีการค้นหาถี่เกินไป กรุณารอสักครู่แล้วลองใหม่",
+        (Lang::Th, TGErrorCode::Internal) => "เกิดข้อผิดพลาดระหว่างค้นหาฎีกา",
+        (Lang::En, TGErrorCode::NotFound) => "No matching Deka was found.",
+        (Lang::En, TGErrorCode::SourceUnavailable) => {
+            "The Deka source is unavailable right now, please try again later."
+        }
+        (Lang::En, TGErrorCode::Timeout) => {
+            "The Deka source took too long to answer, please try again."
+        }
+        (Lang::En, TGErrorCode::SiteLayoutChanged) => {
+            "The Deka source changed its pages and cannot be read at the moment."
+        }
+        (Lang::En, TGErrorCode::InvalidQuery) => "The request could not be understood.",
+        (Lang::En, TGErrorCode::RateLimited) => {
+            "Too many requests to the Deka source, please try again shortly."
+        }
+        (Lang::En, TGErrorCode::Internal) => "Something went wrong while looking up the Deka.",
+    }
+}
+
+pub fn restarting(lang: Lang) -> &'static str {
+    match lang {
+        Lang::Th => "ระบบกำลังเริ่มใหม่ กรุณาลองอีกครั้งในอีกสักครู่",
+        Lang::En => "Worker restarting, please try again shortly.",
+    }
+}
+
+pub fn no_results(lang: Lang) -> &'static str {
+    match lang {
+        Lang::Th => "ไม่พบฎีกา",
+        Lang::En => "No Deka found.",
+    }
+}
+
+/// Display name of a `deka.sources` entry, the entry itself if unknown.
+pub fn source_name(lang: Lang, source: &str) -> String {
+    match (lang, source) {
+        (Lang::Th, "dekasuksa") => "เว็บไซต์ฎีกาศึกษา".to_string(),
+        (Lang::Th, "spc") => "เว็บไซต์ศาลฎีกา".to_string(),
+        (Lang::En, "dekasuksa") => "Deka Suksa website".to_string(),
+        (Lang::En, "spc") => "Supreme Court website".to_string(),
+        _ => source.to_string(),
+    }
+}
+
+pub fn status(lang: Lang, state: &TGJobState) -> String {
+    match (lang, state) {
+        (Lang::Th, TGJobState::Accepted { queue_position: 0 }) => "รับคำขอแล้ว".to_string(),
+        (Lang::Th, TGJobState::Accepted { queue_position }) => {
+            format!("อยู่ในคิว ลำดับที่ {}", queue_position)
+        }
+        (Lang::Th, TGJobState::Running { source }) => {
+            format!("กำลังค้นหาจาก{}", source_name(lang, source))
+        }
+        (Lang::Th, TGJobState::Progress { step }) => match step {
+            TGJobStep::Searching => "กำลังค้นหา",
+            TGJobStep::FetchingLongNotes => "กำลังอ่านฎีกาฉบับย่อยาว",
+            TGJobStep::OpeningPrintPage => "กำลังเปิดหน้าพิมพ์",
+        }
+        .to_string(),
+        (Lang::Th, TGJobState::Cancelled { superseded_by }) => match superseded_by {
+            None => "ยกเลิกการค้นหาแล้ว",
+            Some(_) => "แทนที่ด้วยการค้นหาใหม่แล้ว",
+        }
+        .to_string(),
+        (Lang::En, TGJobState::Accepted { queue_position: 0 }) => "Request accepted.".to_string(),
+        (Lang::En, TGJobState::Accepted { queue_position }) => {
+            format!("Queued, number {} in line.", queue_position)
+        }
+        (Lang::En, TGJobState::Running { source }) => {
+            format!("Searching the {}.", source_name(lang, source))
+        }
+        (Lang::En, TGJobState::Progress { step }) => match step {
+            TGJobStep::Searching => "Searching.",
+            TGJobStep::FetchingLongNotes => "Reading the full texts.",
+            TGJobStep::OpeningPrintPage => "Opening the print page.",
+        }
+        .to_string(),
+        (Lang::En, TGJobState::Cancelled { superseded_by }) => match superseded_by {
+            None => "Lookup cancelled.",
+            Some(_) => "Replaced by a newer lookup.",
+        }
+        .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lang_from_code() {
+        assert_eq!(Lang::from_code(None), Lang::Th);
+        assert_eq!(Lang::from_code(Some("")), Lang::Th);
+        assert_eq!(Lang::from_code(Some("th")), Lang::Th);
+        assert_eq!(Lang::from_code(Some("TH-th")), Lang::Th);
+        assert_eq!(Lang::from_code(Some("en-US")), Lang::En);
+        assert_eq!(Lang::from_code(Some("ja")), Lang::En);
+    }
+
+    #[test]
+    fn texts_per_lang() {
+        let running = TGJobState::Running {
+            source: "dekasuksa".to_string(),
+        };
+
+        assert_eq!(status(Lang::Th, &running), "กำลังค้นหาจากเว็บไซต์ฎีกาศึกษา");
+        assert_eq!(
+            status(Lang::En, &running),
+            "Searching the Deka Suksa website."
+        );
+        assert_eq!(source_name(Lang::En, "other"), "other");
+        assert_ne!(
+            error(Lang::Th, TGErrorCode::Timeout),
+            error(Lang::En, TGErrorCode::Timeout)
+        );
+    }
+}
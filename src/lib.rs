@@ -1,4 +1,9 @@
+pub mod config;
 pub mod error;
+pub mod i18n;
+pub mod logging;
 pub mod model;
+pub mod render;
+pub mod schema;
 pub mod service;
 pub mod util;
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter,
+    Layer, Registry,
+};
+
+use crate::{error, util};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `EnvFilter` directives, e.g. `info,deka_supremecourt_rs::service::deka=debug`
+    pub level: String,
+    pub format: LogFormat,
+    /// Write here instead of stdout. Rotated files get a date suffix.
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// Rotated files to keep, 0 keeps all of them
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+            file: None,
+            rotation: LogRotation::default(),
+            max_files: 7,
+        }
+    }
+}
+
+fn appender(config: &LogConfig, path: &Path) -> util::Result<RollingFileAppender> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let prefix = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("worker.log");
+    let rotation = match config.rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix);
+    if config.max_files > 0 {
+        builder = builder.max_log_files(config.max_files);
+    }
+
+    builder.build(dir).context(error::LogFileSnafu)
+}
+
+/// Handle on the installed subscriber. Keep it alive until exit,
+/// dropping it flushes the file writer.
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    _guard: Option<WorkerGuard>,
+}
+
+impl Logging {
+    /// Swap the filter directives without restarting.
+    pub fn set_level(&self, level: &str) -> util::Result<()> {
+        let filter = EnvFilter::try_new(level).context(error::LogFilterSnafu)?;
+
+        self.filter.reload(filter).context(error::LogReloadSnafu)
+    }
+}
+
+/// Install the global subscriber.
+pub fn init(config: &LogConfig) -> util::Result<Logging> {
+    let filter = EnvFilter::try_new(&config.level).context(error::LogFilterSnafu)?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (writer, guard) = match &config.file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(config, path)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(config.file.is_none())
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(false);
+    let fmt = match config.format {
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Json => fmt.json().flatten_event(true).with_target(true).boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .try_init()
+        .ok()
+        .context(error::GlobalDefautSnafu)?;
+
+    Ok(Logging {
+        filter: handle,
+        _guard: guard,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn file_appender_writes() {
+        let dir = std::env::temp_dir().join(format!("log-test-{}", std::process::id()));
+        let config = LogConfig {
+            rotation: LogRotation::Never,
+            ..Default::default()
+        };
+
+        let mut appender = appender(&config, &dir.join("worker.log")).unwrap();
+        writeln!(appender, "hello").unwrap();
+        appender.flush().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("worker.log")).unwrap(),
+            "hello\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_config_from_toml() {
+        let config: LogConfig =
+            toml::from_str("level = \"warn,deka_supremecourt_rs=debug\"\nformat = \"json\"")
+                .unwrap();
+
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(config.rotation, LogRotation::Daily);
+        assert!(EnvFilter::try_new(&config.level).is_ok());
+    }
+}
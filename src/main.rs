@@ -1,125 +1,516 @@
-use std::{env, str::FromStr};
+use std::time::{Duration, Instant};
 
-use async_tungstenite::{
-    tokio::connect_async,
-    tungstenite::{handshake::client, http::Request, Message},
-};
+use async_tungstenite::tungstenite::Message;
+use clap::Parser;
 use deka_supremecourt_rs::{
-    error,
-    model::{MessagePayload, TGResponse},
-    service::deka,
+    config::{Changes, Cli, Config},
+    error, logging,
+    model::{PayloadError, TGRequest, TGResponse},
+    schema,
+    service::{
+        bot,
+        dead_letter::DeadLetters,
+        deka::{self, DekaConfig},
+        health::HEALTH,
+        http,
+        metrics::METRICS,
+        spool, systemd, webdriver,
+    },
     util,
 };
 use futures::{prelude::*, SinkExt};
 
-use snafu::{OptionExt, ResultExt};
+use snafu::ResultExt;
 use tokio::{
-    signal::unix::{signal, SignalKind},
-    sync::{broadcast, mpsc},
+    signal::unix::{signal, Signal, SignalKind},
+    sync::{broadcast, mpsc, watch},
     task::LocalSet,
+    time,
 };
-use tracing::level_filters::LevelFilter;
+
+const PROGRESS_TICK: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> util::Result<()> {
-    let ws_addr = env::var("ws_addr").context(error::UnsupportEnvSnafu)?;
-    let token = env::var("token").context(error::UnsupportEnvSnafu)?;
+    let cli = Cli::parse();
+    if let Some(dir) = &cli.write_schema {
+        return schema::write(dir);
+    }
+    let mut config = Config::load(&cli)?;
+
+    if cli.print_config {
+        println!("{}", config.to_toml_redacted()?);
+        return Ok(());
+    }
+
+    config.validate()?;
 
     // Setup tracing
-    let sub = tracing_subscriber::fmt()
-        .compact()
-        .with_file(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_target(false)
-        .with_max_level(LevelFilter::from_str("debug").context(error::LevelFilterSnafu)?)
-        .finish();
-    tracing::subscriber::set_global_default(sub)
-        .ok()
-        .context(error::GlobalDefautSnafu)?;
+    let logging = logging::init(&config.log)?;
     tracing::debug!("init");
 
+    let http_thd = if config.http.enabled {
+        let listener = http::bind(&config.http).await?;
+        tracing::info!("Main | Serving monitoring on {}", config.http.listen);
+        Some(tokio::spawn(http::serve(listener, config.http.clone())))
+    } else {
+        None
+    };
+
     let (sig_tx, _todo_sig_rx) = broadcast::channel(32);
-    let (ws_tx, ws_rx) = mpsc::channel::<MessagePayload>(1000);
+    let (ws_tx, ws_rx) = mpsc::channel::<TGRequest>(1000);
     let (tg_tx, mut tg_rx) = mpsc::channel::<TGResponse>(1000);
     let mut sigterm = signal(SignalKind::terminate()).context(error::SignalSnafu)?;
     let mut sigint = signal(SignalKind::interrupt()).context(error::SignalSnafu)?;
+    let mut sighup = signal(SignalKind::hangup()).context(error::SignalSnafu)?;
+    let (deka_tx, deka_rx) = watch::channel(config.deka.clone());
 
     let local_worker = LocalSet::new();
-    let ws_req = Request::builder()
-        .uri(format!("wss://{}/ws", ws_addr))
-        .method("GET")
-        .header("Host", ws_addr)
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Version", "13")
-        .header("Sec-WebSocket-Key", client::generate_key())
-        .header("Authorization", format!("Bearer {}", token))
-        .body(())
-        .context(error::HTTPSnafu)?;
-
-    let (ws_strm, _) = connect_async(client::Request::from(ws_req))
-        .await
-        .context(error::WSSnafu)?;
-    let (mut ws_write, mut ws_read) = ws_strm.split();
+    let mut browser_config = config.browser.clone();
+
+    // Either run the WebDriver ourselves or use one that is already listening
+    let mut drv_thd = match config.browser.webdriver_path.clone() {
+        Some(path) => {
+            tracing::info!("Main | Starting WebDriver");
+            let driver = webdriver::Driver::start(path).await?;
+            browser_config.webdriver_url = driver.url();
+            Some(tokio::spawn(driver.supervise()))
+        }
+        None => None,
+    };
 
     tracing::info!("Main | Starting service thread");
-    let dk_thd = tokio::spawn(deka::deka_thread(sig_tx.subscribe(), ws_rx, tg_tx.clone()));
+    let mut dk_thd = tokio::spawn(deka::deka_thread(
+        sig_tx.subscribe(),
+        ws_rx,
+        tg_tx.clone(),
+        deka_rx,
+        browser_config,
+    ));
+    let mut backoff = config.bot.backoff();
+    let mut spool = spool::Spool::new(
+        config.spool.capacity,
+        Duration::from_secs(config.spool.ttl_secs),
+    );
+    if let Some(path) = config.spool.path.clone() {
+        spool = spool.with_path(path).await?;
+    }
+    let dead_letters = DeadLetters::new(config.spool.dead_letter.clone());
 
     tracing::info!("Main | Starting main loop");
-    local_worker
+    let res = local_worker
         .run_until(async {
-            loop {
-                tokio::select! {
-                    Some(_) = sigterm.recv() => {
-                        tracing::warn!("SIGTERM Shuting down");
-                        let _ = sig_tx.send(());
-                        break;
-                    },
-                    Some(_) = sigint.recv() => {
-                        tracing::warn!("SIGINT Shuting down");
-                        let _ = sig_tx.send(());
-                        break;
-                    },
-                    Some(Ok(msg)) = ws_read.next() => {
-                        if let Some(txt) = match msg {
-                            Message::Text(msg_txt) => Some(msg_txt),
-                            Message::Binary(msg_bin) => String::from_utf8(msg_bin).ok(),
-                            _ => {
-                                tracing::debug!("WS Non-text message: {:?}", msg);
-                                None
+            let mut draining = false;
+            let mut connected = false;
+            let mut new_backoff = false;
+            // Liveness: proves this loop is still being polled
+            let mut notifier = systemd::Notifier::from_env();
+            let mut progress = time::interval(
+                notifier
+                    .watchdog_interval()
+                    .map_or(PROGRESS_TICK, |wd| wd.min(PROGRESS_TICK)),
+            );
+            progress.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            let res = 'conn: loop {
+                HEALTH.set_bot_connected(false);
+                if new_backoff {
+                    backoff = config.bot.backoff();
+                    new_backoff = false;
+                }
+                let (ws_addr, token) = (config.bot.ws_addr.clone(), config.bot.token.clone());
+                // Scoped so the backoff is free again once connected
+                let ws_strm = {
+                    let connect = bot::connect_with_backoff(&ws_addr, &token, &mut backoff);
+                    tokio::pin!(connect);
+
+                    loop {
+                        tokio::select! {
+                            sig = shutdown_signal(&mut sigterm, &mut sigint) => {
+                                if draining {
+                                    tracing::warn!("{} again, stopping now", sig);
+                                    break 'conn Ok(());
+                                }
+
+                                tracing::warn!("{} Shuting down, draining for {:?}", sig, config.deka.grace());
+                                draining = true;
+                                notifier.stopping();
+                                let _ = sig_tx.send(());
+                                continue 'conn;
+                            },
+                            _ = &mut dk_thd, if draining => {
+                                // No link to flush to, keep what is left for the next run
+                                while let Ok(tg_msg) = tg_rx.try_recv() {
+                                    if let Err(e) = spool.push(tg_msg).await {
+                                        tracing::warn!("spool Write error: {:?}", e);
+                                    }
+                                }
+                                break 'conn Ok(());
+                            },
+                            res = driver_exited(&mut drv_thd) => {
+                                tracing::error!("Main | WebDriver supervisor gave up, stopping: {:?}", res);
+                                break 'conn res;
+                            },
+                            _ = progress.tick() => report_progress(&mut notifier, &ws_tx, &tg_rx, &spool),
+                            Some(_) = sighup.recv() => {
+                                if reload(&cli, &mut config, &logging, &deka_tx).bot {
+                                    new_backoff = true;
+                                    continue 'conn;
+                                }
+                            },
+                            res = &mut connect => match res {
+                                Ok(ws_strm) => break ws_strm,
+                                Err(e) => break 'conn Err(e),
+                            },
+                        }
+                    }
+                };
+                tracing::info!("Main | Bot connected");
+                HEALTH.set_bot_connected(true);
+                report_progress(&mut notifier, &ws_tx, &tg_rx, &spool);
+                if connected {
+                    METRICS.ws_reconnects.inc();
+                }
+                connected = true;
+                let (mut ws_write, mut ws_read) = ws_strm.split();
+                let mut heartbeat = config.bot.heartbeat();
+                let mut hb_tick = time::interval_at(
+                    time::Instant::now() + heartbeat.interval(),
+                    heartbeat.interval(),
+                );
+
+                // Tell the bot what we can do before anything else
+                let hello = deka::hello(&config.deka, config.bot.worker_id());
+                if let Err(e) = bot::send(&mut ws_write, &hello).await {
+                    tracing::warn!("ws_write Hello error: {:?}", e);
+                    continue 'conn;
+                }
+
+                // Replay responses that finished while we were away
+                loop {
+                    let tg_msg = match spool.front() {
+                        Ok(Some(tg_msg)) => tg_msg.clone(),
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::warn!("spool Read error: {:?}", e);
+                            break;
+                        }
+                    };
+
+                    match bot::send(&mut ws_write, &tg_msg).await {
+                        Err(e @ error::Error::WSError { .. }) => {
+                            tracing::warn!("spool Replay error: {:?}", e);
+                            continue 'conn;
+                        }
+                        Err(e) => tracing::warn!("spool Dropping unsendable response: {:?}", e),
+                        Ok(_) => {}
+                    }
+
+                    if let Err(e) = spool.pop_front().await {
+                        tracing::warn!("spool Write error: {:?}", e);
+                    }
+                }
+
+                loop {
+                    let pong_deadline = heartbeat.deadline();
+
+                    tokio::select! {
+                        sig = shutdown_signal(&mut sigterm, &mut sigint) => {
+                            if draining {
+                                tracing::warn!("{} again, stopping now", sig);
+                                break 'conn ws_write.close().await.context(error::WSSnafu);
                             }
-                        } {
-                            tracing::debug!("ws_read text message: {:?}", txt);
-
-                            match serde_json::from_str::<MessagePayload>(&txt) {
-                                Ok(pld) => {
-                                    tracing::debug!("ws_read Payload: {:?}", pld);
-                                    while let Err(e) = ws_tx.send(pld.clone()).await.context(error::SendSnafu) {
-                                        tracing::warn!("ws_read Send error: {:?}", e);
+
+                            tracing::warn!("{} Shuting down, draining for {:?}", sig, config.deka.grace());
+                            draining = true;
+                            notifier.stopping();
+                            let _ = sig_tx.send(());
+                        },
+                        _ = &mut dk_thd, if draining => {
+                            tracing::info!("Main | Service thread drained");
+                            while let Ok(tg_msg) = tg_rx.try_recv() {
+                                deliver(&mut ws_write, &mut spool, tg_msg).await;
+                            }
+                            break 'conn ws_write.close().await.context(error::WSSnafu);
+                        },
+                        res = driver_exited(&mut drv_thd) => {
+                            tracing::error!("Main | WebDriver supervisor gave up, stopping: {:?}", res);
+                            let _ = ws_write.close().await;
+                            break 'conn res;
+                        },
+                        _ = progress.tick() => report_progress(&mut notifier, &ws_tx, &tg_rx, &spool),
+                        Some(_) = sighup.recv() => {
+                            let changes = reload(&cli, &mut config, &logging, &deka_tx);
+                            if changes.bot {
+                                tracing::info!("Main | Reconnecting with the new bot settings");
+                                new_backoff = true;
+                                let _ = ws_write.close().await;
+                                break;
+                            }
+                            if changes.deka {
+                                // A reconnect sends a fresh hello, so never spool this one
+                                let hello = deka::hello(&config.deka, config.bot.worker_id());
+                                if let Err(e) = bot::send(&mut ws_write, &hello).await {
+                                    tracing::warn!("ws_write Hello error: {:?}", e);
+                                    break;
+                                }
+                            }
+                        },
+                        ws_msg = ws_read.next() => match ws_msg {
+                            Some(Ok(msg)) => {
+                                if let Some(txt) = match msg {
+                                    Message::Text(msg_txt) => Some(msg_txt),
+                                    Message::Binary(msg_bin) => String::from_utf8(msg_bin).ok(),
+                                    Message::Pong(payload) => {
+                                        heartbeat.on_pong(&payload, Instant::now());
+                                        // A heartbeat got through, so the next drop starts the backoff over
+                                        if heartbeat.latency().is_some() && backoff.attempt() > 0 {
+                                            backoff.reset();
+                                        }
+                                        tracing::debug!("WS Pong latency: {:?}", heartbeat.latency());
+                                        None
+                                    },
+                                    Message::Close(frame) => {
+                                        tracing::warn!("WS Close from server: {:?}", frame);
+                                        break;
+                                    },
+                                    _ => {
+                                        tracing::debug!("WS Non-text message: {:?}", msg);
+                                        None
+                                    }
+                                } {
+                                    tracing::debug!("ws_read text message: {:?}", txt);
+
+                                    match TGRequest::parse(&txt) {
+                                        Ok(TGRequest::Lookup(mut pld)) => {
+                                            let job_id = pld.job_id().to_string();
+                                            tracing::debug!(job_id, "ws_read Payload: {:?}", pld);
+                                            let rejected = if draining {
+                                                Some(pld)
+                                            } else {
+                                                ws_tx.send(TGRequest::Lookup(pld)).await.err().and_then(|e| match e.0 {
+                                                    TGRequest::Lookup(pld) => Some(pld),
+                                                    TGRequest::Cancel(_) => None,
+                                                })
+                                            };
+                                            METRICS.queue_depth("ws_rx", ws_tx.max_capacity() - ws_tx.capacity());
+
+                                            if let Some(pld) = rejected {
+                                                tracing::warn!(job_id, "ws_read Not accepting new lookups");
+                                                if !deliver(&mut ws_write, &mut spool, deka::restarting_response(*pld)).await {
+                                                    break;
+                                                }
+                                            }
+                                        },
+                                        Ok(TGRequest::Cancel(cancel)) => {
+                                            tracing::debug!("ws_read Cancel: {:?}", cancel);
+                                            // Draining cancels whatever is left at the deadline anyway
+                                            if !draining {
+                                                let _ = ws_tx.send(TGRequest::Cancel(cancel)).await;
+                                            }
+                                        },
+                                        Err(PayloadError::Rejected(rejected)) => {
+                                            tracing::warn!(
+                                                job_id = rejected.job_id,
+                                                "ws_read Rejecting request, {}: {}",
+                                                rejected.reason.as_str(),
+                                                rejected.detail
+                                            );
+                                            METRICS.rejected(rejected.reason.as_str());
+                                            if !deliver(&mut ws_write, &mut spool, deka::rejected_response(*rejected)).await {
+                                                break;
+                                            }
+                                        },
+                                        Err(PayloadError::Unparseable(e)) => {
+                                            tracing::warn!("ws_read Deserialize error: {:?}", e);
+                                            if let Err(e) = dead_letters.record(&txt, &e).await {
+                                                tracing::warn!("dead_letter Write error: {:?}", e);
+                                            }
+                                        }
                                     }
-                                },
-                                Err(e) => {
-                                    tracing::warn!("ws_read Deserialize error: {:?}", e);
                                 }
+                            },
+                            Some(Err(e)) => {
+                                tracing::warn!("ws_read Rx error: {:?}", e);
+                                break;
+                            },
+                            None => {
+                                tracing::warn!("ws_read Stream ended");
+                                break;
+                            },
+                        },
+                        _ = sleep_until(pong_deadline) => {
+                            tracing::warn!("ws_write Pong not received in time, dropping connection");
+                            break;
+                        },
+                        _ = hb_tick.tick() => {
+                            if heartbeat.is_dead(Instant::now()) {
+                                tracing::warn!("ws_write Pong not received in time, dropping connection");
+                                break;
                             }
-                        }
-                    },
-                    Some(tg_msg) = tg_rx.recv() => {
-                        if  let Ok(ws_msg) = serde_json::to_string(&tg_msg) {
-                            if let Err(e) = ws_write.send(Message::Text(ws_msg)).await {
-                                tracing::warn!("ws_write Tx error: {:?}", e);
+
+                            if let Err(e) = ws_write.send(Message::Ping(heartbeat.ping(Instant::now()))).await {
+                                tracing::warn!("ws_write Ping error: {:?}", e);
+                                break;
+                            }
+                        },
+                        Some(tg_msg) = tg_rx.recv() => {
+                            METRICS.queue_depth("tg_rx", tg_rx.len());
+                            if !deliver(&mut ws_write, &mut spool, tg_msg).await {
+                                break;
                             }
                         }
                     }
                 }
-            }
+
+                tracing::warn!("Main | Bot connection lost, reconnecting.");
+            };
+            notifier.stopping();
+
+            res
         })
         .await;
-    ws_write.close().await.context(error::WSSnafu)?;
+    HEALTH.set_bot_connected(false);
+    let _ = sig_tx.send(());
     tracing::info!("Main | Stopping Services.");
     local_worker.await;
     dk_thd.abort();
+    if let Some(drv_thd) = drv_thd {
+        drv_thd.abort();
+    }
+    if let Some(http_thd) = http_thd {
+        http_thd.abort();
+    }
     tracing::info!("Main | Services ended.");
-    Ok(())
+    res
+}
+
+async fn shutdown_signal(sigterm: &mut Signal, sigint: &mut Signal) -> &'static str {
+    tokio::select! {
+        Some(_) = sigterm.recv() => "SIGTERM",
+        Some(_) = sigint.recv() => "SIGINT",
+        else => future::pending().await,
+    }
+}
+
+/// Wait for the WebDriver supervisor to stop, forever if there is none.
+/// It only stops when it could not restart the driver, so this is an error.
+async fn driver_exited(
+    drv_thd: &mut Option<tokio::task::JoinHandle<util::Result<()>>>,
+) -> util::Result<()> {
+    let res = match drv_thd {
+        Some(drv_thd) => drv_thd.await.context(error::TokioJoinSnafu),
+        None => future::pending().await,
+    };
+    HEALTH.set_browser_ready(false);
+
+    res.and_then(|res| res.and_then(|_| error::ReconnectSnafu.fail()))
+}
+
+/// Wait for `deadline`, forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(time::Instant::from_std(deadline)).await,
+        None => future::pending().await,
+    }
+}
+
+/// Re-read the config on SIGHUP and apply what can be applied live.
+/// Returns what changed; `bot` means the link has to be re-established.
+fn reload(
+    cli: &Cli,
+    config: &mut Config,
+    logging: &logging::Logging,
+    deka_tx: &watch::Sender<DekaConfig>,
+) -> Changes {
+    let new = match Config::load(cli).and_then(|new| new.validate().map(|_| new)) {
+        Ok(new) => new,
+        Err(e) => {
+            tracing::warn!(
+                "Main | Config reload rejected, keeping the current one: {}",
+                e
+            );
+            return Changes::default();
+        }
+    };
+    let changes = config.changes(&new);
+
+    if changes.log_level {
+        match logging.set_level(&new.log.level) {
+            Ok(_) => config.log.level = new.log.level,
+            Err(e) => tracing::warn!("Main | Log level reload error: {:?}", e),
+        }
+    }
+    if changes.deka {
+        // The executor is sized once, so keep reporting the running value
+        let max_concurrency = config.deka.max_concurrency;
+        config.deka = new.deka;
+        config.deka.max_concurrency = max_concurrency;
+        deka_tx.send_replace(config.deka.clone());
+    }
+    if changes.bot {
+        config.bot = new.bot;
+    }
+    if !changes.restart.is_empty() {
+        tracing::warn!(
+            "Main | Restart to apply changes to: {}",
+            changes.restart.join(", ")
+        );
+    }
+    tracing::info!("Main | Config reloaded");
+
+    changes
+}
+
+/// Liveness beat for `/healthz` and systemd, plus readiness once everything is up.
+fn report_progress(
+    notifier: &mut systemd::Notifier,
+    ws_tx: &mpsc::Sender<TGRequest>,
+    tg_rx: &mpsc::Receiver<TGResponse>,
+    spool: &spool::Spool,
+) {
+    HEALTH.beat();
+    notifier.watchdog();
+    if HEALTH.is_ready() {
+        notifier.ready();
+    }
+    notifier.status(format!(
+        "{}, {} lookup(s) queued, {} response(s) pending, {} spooled",
+        if HEALTH.is_ready() {
+            "Ready"
+        } else {
+            "Starting"
+        },
+        ws_tx.max_capacity() - ws_tx.capacity(),
+        tg_rx.len(),
+        spool.len()
+    ));
+}
+
+/// Send a response to the bot, spooling it if the link is down.
+/// Statuses are not worth keeping and are dropped instead.
+/// Returns `false` when the connection should be dropped.
+async fn deliver(
+    ws_write: &mut bot::WSWrite,
+    spool: &mut spool::Spool,
+    tg_msg: TGResponse,
+) -> bool {
+    match bot::send(ws_write, &tg_msg).await {
+        Err(e @ error::Error::WSError { .. }) if !tg_msg.is_final() => {
+            tracing::warn!("ws_write Tx error, dropping status: {:?}", e);
+            false
+        }
+        Err(e @ error::Error::WSError { .. }) => {
+            tracing::warn!("ws_write Tx error, spooling: {:?}", e);
+            if let Err(e) = spool.push(tg_msg).await {
+                tracing::warn!("spool Write error: {:?}", e);
+            }
+            false
+        }
+        Err(e) => {
+            tracing::warn!("ws_write Serialize error: {:?}", e);
+            true
+        }
+        Ok(_) => true,
+    }
 }
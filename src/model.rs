@@ -1,40 +1,168 @@
+use std::borrow::Cow;
+
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+use crate::{
+    i18n::{self, Labels, Lang},
+    render::{self, ParseMode},
+};
+
+/// Bot API fields this worker does not read. Kept so an update goes back
+/// to the bot exactly as it came in.
+pub type TGExtra = serde_json::Map<String, serde_json::Value>;
+
+/// Bot API `User`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGUser {
-    pub id: u64,
+    pub id: i64,
     pub is_bot: bool,
     pub first_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub last_name: Option<String>,
-    pub username: String,
-    pub language_code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+    #[serde(flatten)]
+    pub extra: TGExtra,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TGChatType {
+    #[default]
+    Private,
+    Group,
+    Supergroup,
+    Channel,
+    /// A type added to the Bot API after this worker was written
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Bot API `Chat`. Group and channel IDs are negative.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGChat {
-    pub id: u64,
-    pub username: String,
-    pub first_name: String,
+    pub id: i64,
     #[serde(rename = "type")]
-    pub chat_type: String,
+    pub chat_type: TGChatType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_forum: Option<bool>,
+    #[serde(flatten)]
+    pub extra: TGExtra,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Bot API `MessageEntity`. Offsets and lengths are in UTF-16 code units.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct TGMessageEntity {
+    #[serde(rename = "type")]
+    pub entity_type: String,
+    pub offset: u32,
+    pub length: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<TGUser>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(flatten)]
+    pub extra: TGExtra,
+}
+
+/// Bot API `Message`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGMessageInfo {
-    pub message_id: u64,
-    pub from: TGUser,
+    pub message_id: i64,
+    /// Forum topic the message belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_thread_id: Option<i64>,
+    /// Empty for channel posts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<TGUser>,
+    /// Sent on behalf of a chat, e.g. a channel or an anonymous admin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_chat: Option<TGChat>,
     pub chat: TGChat,
-    pub date: u64,
-    pub text: String,
+    pub date: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_topic_message: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to_message: Option<Box<TGMessageInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entities: Option<Vec<TGMessageEntity>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption_entities: Option<Vec<TGMessageEntity>>,
+    #[serde(flatten)]
+    pub extra: TGExtra,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Bot API `Update`. At most one of the message fields is set.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGMessgae {
-    pub update_id: u64,
-    pub message: TGMessageInfo,
+    pub update_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<TGMessageInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_message: Option<TGMessageInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_post: Option<TGMessageInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_channel_post: Option<TGMessageInfo>,
+    #[serde(flatten)]
+    pub extra: TGExtra,
+}
+
+impl TGMessgae {
+    /// Whichever message this update carries.
+    pub fn effective_message(&self) -> Option<&TGMessageInfo> {
+        self.message
+            .as_ref()
+            .or(self.edited_message.as_ref())
+            .or(self.channel_post.as_ref())
+            .or(self.edited_channel_post.as_ref())
+    }
+
+    /// Chat and sender of the message, so requests from one person in a
+    /// group are told apart from another's.
+    pub fn sender(&self) -> Option<(i64, i64)> {
+        let msg = self.effective_message()?;
+        let from = msg
+            .from
+            .as_ref()
+            .map(|u| u.id)
+            .or(msg.sender_chat.as_ref().map(|c| c.id))
+            .unwrap_or(msg.chat.id);
+
+        Some((msg.chat.id, from))
+    }
+
+    /// Language to answer in, from the sender's `language_code`.
+    pub fn lang(&self) -> Lang {
+        let code = self
+            .effective_message()
+            .and_then(|msg| msg.from.as_ref())
+            .and_then(|from| from.language_code.as_deref());
+
+        Lang::from_code(code)
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct TGDekaNumber {
     pub deka_serial: String,
@@ -42,7 +170,7 @@ pub struct TGDekaNumber {
     pub with_long_note: bool,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct TGDekaSearch {
     pub search_words: Vec<String>,
@@ -53,26 +181,207 @@ pub struct TGDekaSearch {
     pub with_long_note: bool,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 #[serde(tag = "mode", rename_all = "camelCase")]
 pub enum TGDeka {
     Number(TGDekaNumber),
     Search(TGDekaSearch),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+impl TGDeka {
+    /// Every `mode` this worker understands.
+    pub const MODES: &'static [&'static str] = &["number", "search"];
+}
+
+/// Version of the `MessagePayload`/`TGResponse` wire format. Only changes an
+/// older peer would misread bump it; new optional fields and new `TGResponse`
+/// variants do not.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct MessagePayload {
     pub message: TGMessgae,
     pub info: TGDeka,
+    /// Correlates log lines and the response with this request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    /// Version the bot wrote this request for, `1` when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+    /// Ask every source and send `Partial` results as they come in
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    /// Also send the result as Telegram messages written for this mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
+}
+
+/// `MessagePayload` with `info` left unparsed, so a request with a bad
+/// `info` can still be answered.
+#[derive(Deserialize)]
+struct Envelope {
+    message: TGMessgae,
+    #[serde(default)]
+    info: serde_json::Value,
+    #[serde(default)]
+    job_id: Option<String>,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    parse_mode: serde_json::Value,
+}
+
+/// A request whose update parsed but whose `info` did not.
+#[derive(Debug)]
+pub struct RejectedPayload {
+    pub message: TGMessgae,
+    pub job_id: Option<String>,
+    pub reason: TGErrorReason,
+    pub detail: String,
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    /// Can be answered with a `TGResponse::Err`
+    Rejected(Box<RejectedPayload>),
+    /// Not even the update parsed, nobody to answer
+    Unparseable(serde_json::Error),
+}
+
+/// Sent by the bot to stop a request, found by `job_id` or `update_id`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct TGCancel {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_id: Option<i64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(inline)]
+struct CancelEnvelope {
+    cancel: TGCancel,
+}
+
+/// Anything the bot sends.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TGRequest {
+    Lookup(Box<MessagePayload>),
+    Cancel(TGCancel),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+impl JsonSchema for TGRequest {
+    fn schema_name() -> Cow<'static, str> {
+        "TGRequest".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "Anything the bot sends",
+            "anyOf": [
+                gen.subschema_for::<MessagePayload>(),
+                gen.subschema_for::<CancelEnvelope>(),
+            ],
+        })
+    }
+}
+
+impl TGRequest {
+    /// `{"cancel": {...}}` is a cancel, anything else a `MessagePayload`.
+    pub fn parse(txt: &str) -> Result<Self, PayloadError> {
+        match serde_json::from_str::<CancelEnvelope>(txt) {
+            Ok(envelope) => Ok(TGRequest::Cancel(envelope.cancel)),
+            Err(_) => MessagePayload::parse(txt).map(|pld| TGRequest::Lookup(Box::new(pld))),
+        }
+    }
+}
+
+impl MessagePayload {
+    /// Parse the update first and `info` second, so that only requests
+    /// without a usable update are lost.
+    pub fn parse(txt: &str) -> Result<Self, PayloadError> {
+        let envelope: Envelope = serde_json::from_str(txt).map_err(PayloadError::Unparseable)?;
+
+        if let Some(version) = envelope.protocol_version.filter(|v| *v > PROTOCOL_VERSION) {
+            return Err(PayloadError::Rejected(Box::new(RejectedPayload {
+                message: envelope.message,
+                job_id: envelope.job_id,
+                reason: TGErrorReason::UnsupportedVersion,
+                detail: format!(
+                    "protocol version {} is newer than {}",
+                    version, PROTOCOL_VERSION
+                ),
+            })));
+        }
+
+        let parse_mode = match Option::<ParseMode>::deserialize(&envelope.parse_mode) {
+            Ok(parse_mode) => parse_mode,
+            Err(e) => {
+                return Err(PayloadError::Rejected(Box::new(RejectedPayload {
+                    message: envelope.message,
+                    job_id: envelope.job_id,
+                    reason: TGErrorReason::InvalidRequest,
+                    detail: format!("parse_mode: {}", e),
+                })));
+            }
+        };
+
+        match TGDeka::deserialize(&envelope.info) {
+            Ok(info) => Ok(Self {
+                message: envelope.message,
+                info,
+                job_id: envelope.job_id,
+                protocol_version: envelope.protocol_version,
+                stream: envelope.stream,
+                parse_mode,
+            }),
+            Err(e) => {
+                let reason = match envelope.info.get("mode").and_then(|m| m.as_str()) {
+                    Some(mode) if !TGDeka::MODES.contains(&mode) => TGErrorReason::UnsupportedMode,
+                    _ => TGErrorReason::InvalidRequest,
+                };
+
+                Err(PayloadError::Rejected(Box::new(RejectedPayload {
+                    message: envelope.message,
+                    job_id: envelope.job_id,
+                    reason,
+                    detail: e.to_string(),
+                })))
+            }
+        }
+    }
+
+    /// Job ID of this request, generated the first time it is asked for.
+    pub fn job_id(&mut self) -> &str {
+        self.job_id
+            .get_or_insert_with(|| format!("{:016x}", rand::random::<u64>()))
+    }
+
+    /// Span that every log line of this request is recorded under.
+    pub fn span(&mut self) -> tracing::Span {
+        let job_id = self.job_id().to_string();
+
+        tracing::info_span!(
+            "job",
+            job_id = %job_id,
+            update_id = self.message.update_id,
+            chat_id = self.message.effective_message().map(|m| m.chat.id),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct DekaMetadata {
     pub law: String,
     pub source: String,
+    /// Page the deka was read from, when it has one of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct DekaInfo {
     pub deka_no: String,
     pub short_note: String,
@@ -80,21 +389,603 @@ pub struct DekaInfo {
     pub metadata: DekaMetadata,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGResponseOkay {
     pub from: String,
     pub message: TGMessgae,
     pub result: Option<Vec<DekaInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    /// What to tell the user when `result` is empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Labels>,
+    /// Set when the request asked for `parts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
+    /// `result`, or `text` when there is none, as messages ready to send
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>,
+}
+
+impl TGResponseOkay {
+    pub fn new(message: TGMessgae, job_id: Option<String>, result: Option<Vec<DekaInfo>>) -> Self {
+        let lang = message.lang();
+
+        Self {
+            from: "deka".to_string(),
+            text: match result {
+                Some(_) => None,
+                None => Some(i18n::no_results(lang).to_string()),
+            },
+            labels: result.as_ref().map(|_| Labels::new(lang)),
+            message,
+            result,
+            job_id,
+            parse_mode: None,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Fill `parts` for `parse_mode`, if any.
+    pub fn rendered(mut self, parse_mode: Option<ParseMode>) -> Self {
+        let Some(mode) = parse_mode else {
+            return self;
+        };
+
+        self.parts = match (&self.result, &self.labels, &self.text) {
+            (Some(result), Some(labels), _) => render::render(mode, labels, result),
+            (_, _, Some(text)) => vec![mode.escape(text)],
+            _ => Vec::new(),
+        };
+        self.parse_mode = parse_mode;
+
+        self
+    }
+}
+
+/// Results so far for a request sent with `stream`. Each one holds all
+/// that was found yet, so the bot can redraw its message from the latest.
+/// The final `Okay` follows with the same `job_id`.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct TGResponsePartial {
+    pub from: String,
+    pub message: TGMessgae,
+    pub job_id: String,
+    /// Counts up from 1 within a job
+    pub seq: u32,
+    /// Source that added to the result this time
+    pub source: String,
+    pub result: Vec<DekaInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Labels>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
+    /// `result` as messages ready to send, when the request asked for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>,
+}
+
+/// Machine-readable reason a request was refused.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TGErrorReason {
+    /// `info.mode` is not in `TGDeka::MODES`
+    UnsupportedMode,
+    /// `info` is missing or has a field of the wrong type
+    InvalidRequest,
+    /// `protocol_version` is newer than `PROTOCOL_VERSION`
+    UnsupportedVersion,
+}
+
+impl TGErrorReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TGErrorReason::UnsupportedMode => "unsupported_mode",
+            TGErrorReason::InvalidRequest => "invalid_request",
+            TGErrorReason::UnsupportedVersion => "unsupported_version",
+        }
+    }
+}
+
+/// Stable error taxonomy the bot can act on.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TGErrorCode {
+    NotFound,
+    /// A source or the browser could not be reached
+    SourceUnavailable,
+    Timeout,
+    /// A source answered with pages we could not read
+    SiteLayoutChanged,
+    /// The request itself was wrong, see `reason`
+    InvalidQuery,
+    RateLimited,
+    #[default]
+    Internal,
 }
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+
+impl TGErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TGErrorCode::NotFound => "not_found",
+            TGErrorCode::SourceUnavailable => "source_unavailable",
+            TGErrorCode::Timeout => "timeout",
+            TGErrorCode::SiteLayoutChanged => "site_layout_changed",
+            TGErrorCode::InvalidQuery => "invalid_query",
+            TGErrorCode::RateLimited => "rate_limited",
+            TGErrorCode::Internal => "internal",
+        }
+    }
+
+    /// Whether the same request may work if sent again later.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            TGErrorCode::SourceUnavailable | TGErrorCode::Timeout | TGErrorCode::RateLimited
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct TGResponseErr {
     pub from: String,
     pub message: TGMessgae,
+    /// Safe to show to the user, in their language
     pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TGErrorReason>,
+    #[serde(default)]
+    pub code: TGErrorCode,
+    #[serde(default)]
+    pub retryable: bool,
+    /// For logs only, may name internal URLs and errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl TGResponseErr {
+    pub fn new(message: TGMessgae, job_id: Option<String>, code: TGErrorCode) -> Self {
+        Self {
+            from: "deka".to_string(),
+            error: i18n::error(message.lang(), code).to_string(),
+            message,
+            job_id,
+            reason: None,
+            code,
+            retryable: code.retryable(),
+            details: None,
+        }
+    }
+}
+
+/// What this worker can do, sent once each time the WebSocket connects.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct TGHello {
+    pub protocol_version: u32,
+    pub worker_id: String,
+    pub worker_version: String,
+    /// `TGDeka` modes accepted
+    pub modes: Vec<String>,
+    /// Sources tried, in order
+    pub sources: Vec<String>,
+    /// Lookups run at the same time
+    pub max_concurrency: usize,
+}
+
+/// A step inside a source, for "opening print page" style updates.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TGJobStep {
+    /// Search submitted, waiting for the result page
+    Searching,
+    FetchingLongNotes,
+    OpeningPrintPage,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TGJobState {
+    /// Queued; `0` means it starts right away
+    Accepted {
+        queue_position: usize,
+    },
+    /// Asking `source`, e.g. `dekasuksa` or `spc`
+    Running {
+        source: String,
+    },
+    Progress {
+        step: TGJobStep,
+    },
+    /// Stopped by a `TGCancel`, or by a newer request from the same sender
+    /// when `deka.supersede` is on. Nothing follows it.
+    Cancelled {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        superseded_by: Option<String>,
+    },
+}
+
+/// Sent while a request is being worked on. The final `Okay` or `Err`
+/// follows with the same `job_id`, unless the job is cancelled.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub struct TGJobStatus {
+    pub from: String,
+    pub message: TGMessgae,
+    pub job_id: String,
+    #[serde(flatten)]
+    pub state: TGJobState,
+    /// `state` in the sender's language
+    #[serde(default)]
+    pub text: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+impl TGJobStatus {
+    pub fn new(message: TGMessgae, job_id: String, state: TGJobState) -> Self {
+        Self {
+            from: "deka".to_string(),
+            text: i18n::status(message.lang(), &state),
+            message,
+            job_id,
+            state,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub enum TGResponse {
     Okay(TGResponseOkay),
     Err(TGResponseErr),
+    Hello(TGHello),
+    Status(TGJobStatus),
+    Partial(TGResponsePartial),
+}
+
+impl TGResponse {
+    /// Worth spooling and replaying after a reconnect. Hellos and statuses
+    /// are stale by then, except for the one that ends a cancelled job.
+    pub fn is_final(&self) -> bool {
+        match self {
+            TGResponse::Okay(_) | TGResponse::Err(_) => true,
+            TGResponse::Status(status) => {
+                matches!(status.state, TGJobState::Cancelled { .. })
+            }
+            TGResponse::Hello(_) | TGResponse::Partial(_) => false,
+        }
+    }
+
+    pub fn job_id(&self) -> Option<&str> {
+        match self {
+            TGResponse::Okay(res) => res.job_id.as_deref(),
+            TGResponse::Err(res) => res.job_id.as_deref(),
+            TGResponse::Status(status) => Some(&status.job_id),
+            TGResponse::Partial(partial) => Some(&partial.job_id),
+            TGResponse::Hello(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    use super::*;
+
+    pub fn message(update_id: i64) -> TGMessgae {
+        TGMessgae {
+            update_id,
+            message: Some(TGMessageInfo {
+                message_id: update_id,
+                from: Some(TGUser {
+                    id: 123456,
+                    first_name: "test".to_string(),
+                    username: Some("tester".to_string()),
+                    language_code: Some("en".to_string()),
+                    ..Default::default()
+                }),
+                chat: TGChat {
+                    id: 123456,
+                    chat_type: TGChatType::Private,
+                    first_name: Some("test".to_string()),
+                    username: Some("tester".to_string()),
+                    ..Default::default()
+                },
+                date: 12334554,
+                text: Some("ฎีกา 264/2567".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    pub fn payload(update_id: i64) -> MessagePayload {
+        MessagePayload {
+            message: message(update_id),
+            info: TGDeka::Number(TGDekaNumber {
+                deka_serial: "264".to_string(),
+                deka_year: 2567,
+                with_long_note: false,
+            }),
+            job_id: None,
+            protocol_version: None,
+            stream: false,
+            parse_mode: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    fn corpus() -> Vec<(String, serde_json::Value)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/updates");
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        files
+            .into_iter()
+            .map(|path| {
+                let json = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+                (path.display().to_string(), json)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn updates_round_trip() {
+        for (name, json) in corpus() {
+            let update: TGMessgae =
+                serde_json::from_value(json.clone()).unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            assert!(update.effective_message().is_some(), "{}", name);
+            assert_eq!(serde_json::to_value(&update).unwrap(), json, "{}", name);
+        }
+    }
+
+    #[test]
+    fn updates_from_groups_and_channels() {
+        let corpus = corpus()
+            .into_iter()
+            .map(|(name, json)| (name, serde_json::from_value(json).unwrap()))
+            .collect::<Vec<(String, TGMessgae)>>();
+        let update = |file: &str| &corpus.iter().find(|(n, _)| n.ends_with(file)).unwrap().1;
+
+        let msg = update("supergroup_topic_reply.json")
+            .message
+            .as_ref()
+            .unwrap();
+        assert_eq!(msg.chat.id, -1001987654321);
+        assert_eq!(msg.chat.chat_type, TGChatType::Supergroup);
+        assert_eq!(msg.message_thread_id, Some(3310));
+        assert_eq!(msg.reply_to_message.as_ref().unwrap().message_id, 3388);
+        assert_eq!(msg.from.as_ref().unwrap().username, None);
+
+        let post = update("channel_post.json").effective_message().unwrap();
+        assert_eq!(post.chat.chat_type, TGChatType::Channel);
+        assert!(post.from.is_none());
+        assert_eq!(post.entities.as_ref().unwrap().len(), 2);
+
+        let edit = update("edited_message.json");
+        assert!(edit.message.is_none());
+        assert_eq!(
+            edit.effective_message().unwrap().edit_date,
+            Some(1716966090)
+        );
+
+        let photo = update("photo_caption.json").effective_message().unwrap();
+        assert!(photo.text.is_none());
+        assert!(photo.extra.contains_key("photo"));
+    }
+
+    #[test]
+    fn unknown_chat_type_parses() {
+        let mut json = corpus()
+            .into_iter()
+            .find(|(n, _)| n.ends_with("supergroup_topic_reply.json"))
+            .unwrap()
+            .1;
+        json["message"]["chat"]["type"] = "business".into();
+        let update: TGMessgae = serde_json::from_value(json).unwrap();
+
+        assert_eq!(update.message.unwrap().chat.chat_type, TGChatType::Unknown);
+    }
+
+    #[test]
+    fn parse_rejects_bad_info_but_keeps_message() {
+        let mut json = serde_json::to_value(fixture::payload(7)).unwrap();
+        json["job_id"] = "abc".into();
+        let pld = MessagePayload::parse(&json.to_string()).unwrap();
+        assert_eq!(pld.job_id.as_deref(), Some("abc"));
+
+        let reason = |info: serde_json::Value| {
+            let mut json = json.clone();
+            json["info"] = info;
+            match MessagePayload::parse(&json.to_string()) {
+                Err(PayloadError::Rejected(rejected)) => {
+                    assert_eq!(rejected.message.update_id, 7);
+                    assert_eq!(rejected.job_id.as_deref(), Some("abc"));
+                    rejected.reason
+                }
+                res => panic!("{:?}", res),
+            }
+        };
+        assert_eq!(
+            reason(serde_json::json!({"mode": "citation"})),
+            TGErrorReason::UnsupportedMode
+        );
+        assert_eq!(
+            reason(serde_json::json!({"mode": "number", "dekaSerial": 264})),
+            TGErrorReason::InvalidRequest
+        );
+        assert_eq!(
+            reason(serde_json::Value::Null),
+            TGErrorReason::InvalidRequest
+        );
+
+        let mut newer = json.clone();
+        newer["protocol_version"] = (PROTOCOL_VERSION + 1).into();
+        assert!(matches!(
+            MessagePayload::parse(&newer.to_string()),
+            Err(PayloadError::Rejected(r)) if r.reason == TGErrorReason::UnsupportedVersion
+        ));
+        newer["protocol_version"] = PROTOCOL_VERSION.into();
+        assert!(MessagePayload::parse(&newer.to_string()).is_ok());
+
+        assert!(matches!(
+            MessagePayload::parse(r#"{"message": {}, "info": {}}"#),
+            Err(PayloadError::Unparseable(_))
+        ));
+        assert!(matches!(
+            MessagePayload::parse("not json"),
+            Err(PayloadError::Unparseable(_))
+        ));
+    }
+
+    #[test]
+    fn job_status_is_flat() {
+        let status = TGResponse::Status(TGJobStatus::new(
+            fixture::message(1),
+            "abc".to_string(),
+            TGJobState::Progress {
+                step: TGJobStep::OpeningPrintPage,
+            },
+        ));
+        let json = serde_json::to_value(&status).unwrap();
+
+        assert_eq!(json["Status"]["state"], "progress");
+        assert_eq!(json["Status"]["step"], "opening_print_page");
+        assert!(!status.is_final());
+        assert_eq!(serde_json::from_value::<TGResponse>(json).unwrap(), status);
+    }
+
+    #[test]
+    fn cancel_or_lookup() {
+        let req = TGRequest::parse(r#"{"cancel": {"job_id": "abc"}}"#).unwrap();
+        assert_eq!(
+            req,
+            TGRequest::Cancel(TGCancel {
+                job_id: Some("abc".to_string()),
+                update_id: None,
+            })
+        );
+
+        let txt = serde_json::to_string(&fixture::payload(3)).unwrap();
+        assert_eq!(
+            TGRequest::parse(&txt).unwrap(),
+            TGRequest::Lookup(Box::new(fixture::payload(3)))
+        );
+
+        let cancelled = TGResponse::Status(TGJobStatus::new(
+            fixture::message(3),
+            "abc".to_string(),
+            TGJobState::Cancelled {
+                superseded_by: None,
+            },
+        ));
+        assert!(cancelled.is_final());
+        assert_eq!(
+            serde_json::to_value(&cancelled).unwrap()["Status"]["state"],
+            "cancelled"
+        );
+    }
+
+    #[test]
+    fn error_codes_on_the_wire() {
+        let err = TGResponseErr::new(fixture::message(1), None, TGErrorCode::RateLimited);
+        let json = serde_json::to_value(&err).unwrap();
+
+        assert_eq!(json["code"], "rate_limited");
+        assert_eq!(json["retryable"], true);
+        assert_eq!(
+            json["error"],
+            i18n::error(Lang::En, TGErrorCode::RateLimited)
+        );
+        assert!(json.get("details").is_none());
+
+        // Responses spooled before codes existed still load
+        let old = serde_json::json!({
+            "from": "deka",
+            "message": json["message"],
+            "error": "Unable to find Deka",
+        });
+        let old: TGResponseErr = serde_json::from_value(old).unwrap();
+        assert_eq!(old.code, TGErrorCode::Internal);
+        assert!(!old.retryable);
+    }
+
+    #[test]
+    fn texts_follow_language_code() {
+        let mut message = fixture::message(1);
+        assert_eq!(message.lang(), Lang::En);
+
+        let okay = TGResponseOkay::new(message.clone(), None, None);
+        assert_eq!(okay.text.as_deref(), Some(i18n::no_results(Lang::En)));
+        assert!(okay.labels.is_none());
+
+        if let Some(from) = message.message.as_mut().and_then(|m| m.from.as_mut()) {
+            from.language_code = None;
+        }
+        assert_eq!(message.lang(), Lang::Th);
+
+        let okay = TGResponseOkay::new(message.clone(), None, Some(Vec::new()));
+        assert!(okay.text.is_none());
+        assert_eq!(okay.labels, Some(Labels::new(Lang::Th)));
+
+        let err = TGResponseErr::new(message, None, TGErrorCode::Timeout);
+        assert_eq!(err.error, i18n::error(Lang::Th, TGErrorCode::Timeout));
+    }
+
+    #[test]
+    fn parse_mode_asks_for_parts() {
+        let mut json = serde_json::to_value(fixture::payload(1)).unwrap();
+        json["parse_mode"] = "HTML".into();
+        let pld = MessagePayload::parse(&json.to_string()).unwrap();
+        assert_eq!(pld.parse_mode, Some(ParseMode::Html));
+
+        let okay = TGResponseOkay::new(pld.message.clone(), None, None).rendered(pld.parse_mode);
+        assert_eq!(okay.parts, vec![i18n::no_results(Lang::En)]);
+        let okay = TGResponseOkay::new(pld.message, None, None).rendered(None);
+        assert!(okay.parts.is_empty() && okay.parse_mode.is_none());
+
+        json["parse_mode"] = "Markdown".into();
+        match MessagePayload::parse(&json.to_string()) {
+            Err(PayloadError::Rejected(rejected)) => {
+                assert_eq!(rejected.reason, TGErrorReason::InvalidRequest)
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn job_id_is_generated_once() {
+        let mut pld = fixture::payload(1);
+        let job_id = pld.job_id().to_string();
+
+        assert_eq!(job_id.len(), 16);
+        assert_eq!(pld.job_id(), job_id);
+        assert_ne!(fixture::payload(1).job_id(), job_id);
+    }
+
+    #[test]
+    fn job_id_is_optional_on_the_wire() {
+        let mut json = serde_json::to_value(fixture::payload(1)).unwrap();
+        assert!(json.get("job_id").is_none());
+
+        json["job_id"] = "abc".into();
+        let pld: MessagePayload = serde_json::from_value(json).unwrap();
+        assert_eq!(pld.job_id.as_deref(), Some("abc"));
+
+        let resp = TGResponse::Err(TGResponseErr::new(
+            pld.message,
+            pld.job_id,
+            TGErrorCode::Internal,
+        ));
+        assert_eq!(serde_json::to_value(&resp).unwrap()["Err"]["job_id"], "abc");
+    }
 }
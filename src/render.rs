@@ -0,0 +1,363 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{i18n::Labels, model::DekaInfo};
+
+/// Telegram's limit on the text of one message.
+pub const MAX_LEN: usize = 4096;
+
+/// Telegram `parse_mode` the parts are written for.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+pub enum ParseMode {
+    MarkdownV2,
+    #[serde(rename = "HTML")]
+    Html,
+}
+
+impl ParseMode {
+    pub fn escape(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+
+        for c in text.chars() {
+            match self {
+                ParseMode::MarkdownV2 => {
+                    if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                ParseMode::Html => match c {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    '"' => out.push_str("&quot;"),
+                    c => out.push(c),
+                },
+            }
+        }
+
+        out
+    }
+
+    fn bold(&self, text: &str) -> String {
+        match self {
+            ParseMode::MarkdownV2 => format!("*{}*", self.escape(text)),
+            ParseMode::Html => format!("<b>{}</b>", self.escape(text)),
+        }
+    }
+
+    fn link(&self, text: &str, url: &str) -> String {
+        match self {
+            ParseMode::MarkdownV2 => {
+                let url = url.replace('\\', "\\\\").replace(')', "\\)");
+                format!("[{}]({})", self.escape(text), url)
+            }
+            ParseMode::Html => {
+                format!("<a href=\"{}\">{}</a>", self.escape(url), self.escape(text))
+            }
+        }
+    }
+}
+
+/// Length as Telegram counts it.
+fn len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Escape `raw` in pieces no longer than `limit` once escaped, breaking at
+/// blank lines, then lines, then spaces, then anywhere.
+fn pieces(mode: ParseMode, raw: &str, limit: usize) -> Vec<String> {
+    const BREAKS: [&str; 3] = ["\n\n", "\n", " "];
+
+    fn split(mode: ParseMode, raw: &str, limit: usize, level: usize, out: &mut Vec<String>) {
+        let escaped = mode.escape(raw);
+        if len(&escaped) <= limit {
+            out.push(escaped);
+            return;
+        }
+
+        let Some(sep) = BREAKS.get(level) else {
+            // No break left, cut between characters
+            let mut cur = String::new();
+            for c in raw.chars() {
+                let c = mode.escape(c.encode_utf8(&mut [0; 4]));
+                if len(&cur) + len(&c) > limit {
+                    out.push(std::mem::take(&mut cur));
+                }
+                cur.push_str(&c);
+            }
+            out.push(cur);
+            return;
+        };
+
+        let mut cur = String::new();
+        for part in raw.split(sep) {
+            let joined = if cur.is_empty() {
+                part.to_string()
+            } else {
+                format!("{}{}{}", cur, sep, part)
+            };
+            if len(&mode.escape(&joined)) <= limit {
+                cur = joined;
+                continue;
+            }
+            if !cur.is_empty() {
+                out.push(mode.escape(&cur));
+            }
+            if len(&mode.escape(part)) <= limit {
+                cur = part.to_string();
+            } else {
+                split(mode, part, limit, level + 1, out);
+                cur = String::new();
+            }
+        }
+        if !cur.is_empty() {
+            out.push(mode.escape(&cur));
+        }
+    }
+
+    let mut out = Vec::new();
+    split(mode, raw.trim(), limit, 0, &mut out);
+    out.retain(|p| !p.trim().is_empty());
+
+    out
+}
+
+/// Header of one deka: number, law and source, linked when there is a URL.
+/// A law too long for one message goes on in the paragraphs after it.
+fn header(mode: ParseMode, labels: &Labels, deka: &DekaInfo, limit: usize) -> Vec<String> {
+    let mut lines = vec![mode.bold(&format!("{} {}", labels.deka_no, deka.deka_no.trim()))];
+
+    let law = deka.metadata.law.trim();
+    if !law.is_empty() {
+        let label = format!("{}:", mode.bold(&labels.law));
+        // Leave room to keep the number and label with the start of the law
+        let room = limit.saturating_sub(len(&lines[0]) + len(&label) + 2);
+        let mut law = pieces(mode, law, room).into_iter();
+
+        if let Some(first) = law.next() {
+            lines.push(format!("{} {}", label, first));
+        }
+        lines.extend(law);
+    }
+
+    let source = deka.metadata.source.trim();
+    let source = match deka.metadata.url.as_deref() {
+        Some(url) => match source.strip_suffix(url).unwrap_or(source).trim_end() {
+            "" => mode.link(url, url),
+            name => mode.link(name, url),
+        },
+        None => mode.escape(source),
+    };
+    if !source.is_empty() {
+        lines.push(format!("{}: {}", mode.bold(&labels.source), source));
+    }
+
+    // One paragraph when it fits, otherwise as many lines in each as fit
+    let mut paras: Vec<String> = Vec::new();
+    for line in lines {
+        match paras.last_mut() {
+            Some(para) if len(para) + 1 + len(&line) <= limit => {
+                para.push('\n');
+                para.push_str(&line);
+            }
+            _ => paras.push(line),
+        }
+    }
+
+    paras
+}
+
+/// Paragraphs of one deka, the header first and each note under its label.
+fn paragraphs(mode: ParseMode, labels: &Labels, deka: &DekaInfo, limit: usize) -> Vec<String> {
+    let mut paras = header(mode, labels, deka, limit);
+    let notes = [
+        (&labels.short_note, Some(&deka.short_note)),
+        (&labels.long_note, deka.long_note.as_ref()),
+    ];
+
+    for (label, note) in notes {
+        let Some(note) = note.filter(|n| !n.trim().is_empty()) else {
+            continue;
+        };
+        let label = mode.bold(label);
+        // Leave room to keep the label with the start of its note
+        let mut note = pieces(mode, note, limit.saturating_sub(len(&label) + 1)).into_iter();
+
+        if let Some(first) = note.next() {
+            paras.push(format!("{}\n{}", label, first));
+        }
+        paras.extend(note);
+    }
+
+    paras
+}
+
+/// Pack `result` into messages of at most `limit`, breaking between
+/// paragraphs. Each deka starts with its header, kept in the same message
+/// as the paragraph after it whenever the two fit.
+pub fn render_with(
+    mode: ParseMode,
+    labels: &Labels,
+    result: &[DekaInfo],
+    limit: usize,
+) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut cur = String::new();
+
+    for deka in result {
+        let mut paras = paragraphs(mode, labels, deka, limit);
+        if paras.len() > 1 && len(&paras[0]) + 2 + len(&paras[1]) <= limit {
+            let first = paras.remove(1);
+            paras[0] = format!("{}\n\n{}", paras[0], first);
+        }
+
+        for para in paras {
+            if cur.is_empty() {
+                cur = para;
+            } else if len(&cur) + 2 + len(&para) <= limit {
+                cur = format!("{}\n\n{}", cur, para);
+            } else {
+                parts.push(std::mem::replace(&mut cur, para));
+            }
+        }
+    }
+    if !cur.is_empty() {
+        parts.push(cur);
+    }
+
+    parts
+}
+
+/// Messages ready to send with `parse_mode` set to `mode`.
+pub fn render(mode: ParseMode, labels: &Labels, result: &[DekaInfo]) -> Vec<String> {
+    render_with(mode, labels, result, MAX_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{i18n::Lang, model::DekaMetadata};
+
+    use super::*;
+
+    fn deka(short_note: &str, long_note: Option<&str>) -> DekaInfo {
+        DekaInfo {
+            deka_no: "264/2567".to_string(),
+            short_note: short_note.to_string(),
+            long_note: long_note.map(|ln| ln.to_string()),
+            metadata: DekaMetadata {
+                law: "ป.พ.พ. ม. 572".to_string(),
+                source: "เว็บไซต์ฎีกาศึกษา".to_string(),
+                url: Some("https://example.com/deka_(1).html".to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn escapes_each_mode() {
+        assert_eq!(
+            ParseMode::MarkdownV2.escape("a_b*[c](d)~`>#+-=|{}.!\\"),
+            "a\\_b\\*\\[c\\]\\(d\\)\\~\\`\\>\\#\\+\\-\\=\\|\\{\\}\\.\\!\\\\"
+        );
+        assert_eq!(
+            ParseMode::Html.escape("<b>\"A&B\"</b>"),
+            "&lt;b&gt;&quot;A&amp;B&quot;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn header_goes_first() {
+        let labels = Labels::new(Lang::En);
+        let parts = render(ParseMode::MarkdownV2, &labels, &[deka("short.", None)]);
+
+        assert_eq!(
+            parts,
+            vec![
+                "*Deka No\\. 264/2567*\n\
+                 *Law*: ป\\.พ\\.พ\\. ม\\. 572\n\
+                 *Source*: [เว็บไซต์ฎีกาศึกษา](https://example.com/deka_(1\\).html)\n\n\
+                 *Summary*\nshort\\."
+            ]
+        );
+
+        // Dekasuksa sources end with their URL, which becomes the link
+        let mut with_url = deka("short.", None);
+        with_url.metadata.source += " https://example.com/deka_(1).html";
+        assert_eq!(render(ParseMode::MarkdownV2, &labels, &[with_url]), parts);
+
+        let parts = render(ParseMode::Html, &labels, &[deka("a < b", None)]);
+        assert!(parts[0].starts_with("<b>Deka No. 264/2567</b>\n<b>Law</b>"));
+        assert!(parts[0].ends_with("<b>Summary</b>\na &lt; b"));
+    }
+
+    #[test]
+    fn splits_at_paragraphs() {
+        let labels = Labels::new(Lang::Th);
+        let para = "ก".repeat(60);
+        let long_note = [para.as_str(); 6].join("\n\n");
+        let result = [deka(&para, Some(&long_note)), deka("สั้น", None)];
+
+        for mode in [ParseMode::MarkdownV2, ParseMode::Html] {
+            let parts = render_with(mode, &labels, &result, 200);
+
+            assert!(parts.len() > 2, "{:?}", parts);
+            assert!(parts.iter().all(|p| len(p) <= 200), "{:?}", parts);
+            assert!(parts[0].contains("264/2567"));
+            // Only whole paragraphs move between parts
+            assert!(parts
+                .iter()
+                .flat_map(|p| p.split("\n\n"))
+                .flat_map(|p| p.lines())
+                .filter(|l| l.starts_with('ก'))
+                .all(|l| l == para));
+            // The second header comes with its note
+            let last = parts.last().unwrap();
+            assert!(last.contains("264/2567") && last.ends_with("สั้น"));
+        }
+    }
+
+    #[test]
+    fn splits_long_paragraphs() {
+        let labels = Labels::new(Lang::En);
+        let words = ["word."; 100].join(" ");
+        let parts = render_with(ParseMode::MarkdownV2, &labels, &[deka(&words, None)], 200);
+
+        assert!(parts.iter().all(|p| len(p) <= 200), "{:?}", parts);
+        // No escape is cut in half
+        assert!(parts.iter().all(|p| !p.ends_with('\\')));
+        assert_eq!(
+            parts
+                .iter()
+                .map(|p| p.matches("word").count())
+                .sum::<usize>(),
+            100
+        );
+
+        let parts = render_with(
+            ParseMode::Html,
+            &labels,
+            &[deka(&"&".repeat(50), None)],
+            200,
+        );
+        assert!(parts.iter().all(|p| len(p) <= 200), "{:?}", parts);
+        assert_eq!(parts.concat().matches("&amp;").count(), 50);
+    }
+
+    #[test]
+    fn splits_long_laws() {
+        let labels = Labels::new(Lang::En);
+        let mut long_law = deka("short.", None);
+        long_law.metadata.law = ["ม. 572"; 1000].join(" ");
+        assert!(len(&long_law.metadata.law) > MAX_LEN);
+
+        for mode in [ParseMode::MarkdownV2, ParseMode::Html] {
+            let parts = render(mode, &labels, &[long_law.clone()]);
+
+            assert!(parts.len() > 1, "{:?}", parts);
+            assert!(parts.iter().all(|p| len(p) <= MAX_LEN));
+            assert!(parts[0].contains("264/2567") && parts[0].contains("Law"));
+            assert_eq!(parts.concat().matches("572").count(), 1000);
+            assert!(parts.last().unwrap().contains("Summary"));
+        }
+    }
+}
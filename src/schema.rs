@@ -0,0 +1,457 @@
+use std::{fmt::Write as _, path::Path};
+
+use schemars::generate::SchemaSettings;
+use serde_json::{json, Map, Value};
+use snafu::ResultExt;
+
+use crate::{
+    error,
+    model::{TGRequest, TGResponse, PROTOCOL_VERSION},
+    util,
+};
+
+/// Where the generated files are kept, relative to the crate root.
+pub const DIR: &str = "schema";
+pub const JSON_FILE: &str = "protocol.schema.json";
+pub const TS_FILE: &str = "protocol.d.ts";
+
+/// Schema of every type the bot sends or receives, derived from the
+/// model's `JsonSchema` impls and keyed by type name.
+pub fn defs() -> Map<String, Value> {
+    let mut gen = SchemaSettings::draft2020_12().into_generator();
+    gen.subschema_for::<TGRequest>();
+    gen.subschema_for::<TGResponse>();
+
+    gen.take_definitions(true)
+}
+
+/// JSON Schema (2020-12) of the protocol, one `$defs` entry per type.
+pub fn json_schema() -> String {
+    let schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": "deka-supremecourt-rs/protocol",
+        "title": format!("Deka worker protocol v{}", PROTOCOL_VERSION),
+        "$defs": defs(),
+    });
+
+    serde_json::to_string_pretty(&schema).unwrap_or_default() + "\n"
+}
+
+fn ts_doc(schema: &Value, indent: &str) -> String {
+    match schema.get("description").and_then(Value::as_str) {
+        Some(doc) => format!(
+            "{}/** {} */\n",
+            indent,
+            doc.split_whitespace().collect::<Vec<_>>().join(" ")
+        ),
+        None => String::new(),
+    }
+}
+
+/// A plain object, written as an interface rather than a type alias.
+fn is_interface(schema: &Value) -> bool {
+    schema.get("type") == Some(&json!("object"))
+        && ["oneOf", "anyOf", "allOf"]
+            .iter()
+            .all(|k| schema.get(*k).is_none())
+}
+
+/// Members of an object, each on its own line with its doc when `indent`
+/// is set, all on one line otherwise.
+fn ts_members(schema: &Value, indent: Option<&str>) -> Vec<String> {
+    let empty = Map::new();
+    let properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut members = properties
+        .iter()
+        .map(|(name, prop)| {
+            let optional = if required.contains(&json!(name)) {
+                ""
+            } else {
+                "?"
+            };
+            let doc = indent.map(|i| ts_doc(prop, i)).unwrap_or_default();
+            format!(
+                "{}{}{}{}: {};",
+                doc,
+                indent.unwrap_or_default(),
+                name,
+                optional,
+                ts_type(prop)
+            )
+        })
+        .collect::<Vec<_>>();
+    // Only objects that keep unknown fields, like `TGExtra`, say so
+    if schema
+        .get("additionalProperties")
+        .is_some_and(|a| a != &json!(false))
+    {
+        members.push(format!(
+            "{}[key: string]: unknown;",
+            indent.unwrap_or_default()
+        ));
+    }
+
+    members
+}
+
+/// TypeScript for one schema, as serde reads and writes it.
+fn ts_type(schema: &Value) -> String {
+    let Some(obj) = schema.as_object() else {
+        return "unknown".to_string();
+    };
+
+    if let Some(value) = obj.get("const") {
+        return value.to_string();
+    }
+    if let Some(Value::Array(values)) = obj.get("enum") {
+        return values
+            .iter()
+            .map(Value::to_string)
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+
+    // Each part with whether it is a union, all of them intersected
+    let mut parts: Vec<(String, bool)> = Vec::new();
+    // Tagged variants of a struct are the struct plus the tag
+    if let Some(name) = obj.get("$ref").and_then(Value::as_str) {
+        parts.push((name.trim_start_matches("#/$defs/").to_string(), false));
+    }
+    match obj.get("type") {
+        Some(Value::String(ty)) => parts.push((ts_primitive(ty, schema), false)),
+        Some(Value::Array(types)) => parts.push((
+            types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|ty| ts_primitive(ty, schema))
+                .collect::<Vec<_>>()
+                .join(" | "),
+            types.len() > 1,
+        )),
+        _ => {}
+    }
+    for (key, sep) in [("oneOf", " | "), ("anyOf", " | "), ("allOf", " & ")] {
+        if let Some(Value::Array(schemas)) = obj.get(key) {
+            let joined = schemas.iter().map(ts_type).collect::<Vec<_>>();
+            parts.push((joined.join(sep), sep == " | " && joined.len() > 1));
+        }
+    }
+
+    match parts.len() {
+        0 => "unknown".to_string(),
+        1 => parts.remove(0).0,
+        _ => parts
+            .into_iter()
+            .map(|(part, union)| if union { format!("({})", part) } else { part })
+            .collect::<Vec<_>>()
+            .join(" & "),
+    }
+}
+
+fn ts_primitive(ty: &str, schema: &Value) -> String {
+    match ty {
+        "string" => "string".to_string(),
+        "integer" | "number" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "null" => "null".to_string(),
+        "array" => match schema.get("items") {
+            Some(items) => format!("Array<{}>", ts_type(items)),
+            None => "unknown[]".to_string(),
+        },
+        "object" => format!("{{ {} }}", ts_members(schema, None).join(" ")),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// TypeScript declarations of the protocol, one per type.
+pub fn typescript() -> String {
+    let mut out = format!(
+        "// Generated by `deka-supremecourt-rs --write-schema {}`. Do not edit.\n\
+         // Deka worker protocol v{}\n",
+        DIR, PROTOCOL_VERSION
+    );
+
+    for (name, schema) in defs() {
+        let _ = write!(out, "\n{}", ts_doc(&schema, ""));
+        if is_interface(&schema) {
+            let _ = writeln!(out, "export interface {} {{", name);
+            for member in ts_members(&schema, Some("  ")) {
+                let _ = writeln!(out, "{}", member);
+            }
+            let _ = writeln!(out, "}}");
+        } else {
+            let _ = writeln!(out, "export type {} = {};", name, ts_type(&schema));
+        }
+    }
+
+    out
+}
+
+/// Write the JSON Schema and TypeScript files into `dir`.
+pub fn write(dir: &Path) -> util::Result<()> {
+    std::fs::create_dir_all(dir).context(error::IOSnafu)?;
+    std::fs::write(dir.join(JSON_FILE), json_schema()).context(error::IOSnafu)?;
+    std::fs::write(dir.join(TS_FILE), typescript()).context(error::IOSnafu)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        i18n::Lang,
+        model::{
+            fixture, DekaInfo, DekaMetadata, MessagePayload, TGDeka, TGDekaSearch, TGErrorCode,
+            TGErrorReason, TGHello, TGJobState, TGJobStatus, TGJobStep, TGMessgae, TGResponseErr,
+            TGResponseOkay, TGResponsePartial,
+        },
+        render::ParseMode,
+    };
+
+    use super::*;
+
+    /// Whether `value` passes `schema`, for the keywords `defs` uses.
+    fn check(
+        schema: &Value,
+        value: &Value,
+        defs: &Map<String, Value>,
+        path: &str,
+    ) -> Result<(), String> {
+        let wrong = |why: &str| Err(format!("{}: {} {}", path, value, why));
+        let Some(obj) = schema.as_object() else {
+            return match schema {
+                Value::Bool(false) => wrong("is not allowed"),
+                _ => Ok(()),
+            };
+        };
+
+        if let Some(name) = obj.get("$ref").and_then(Value::as_str) {
+            let name = name.trim_start_matches("#/$defs/");
+            let def = defs.get(name).ok_or_else(|| format!("no def {}", name))?;
+            check(def, value, defs, path)?;
+        }
+        if obj.get("const").is_some_and(|c| c != value) {
+            return wrong("is not the const");
+        }
+        if let Some(Value::Array(values)) = obj.get("enum") {
+            if !values.contains(value) {
+                return wrong("is not in the enum");
+            }
+        }
+        if let Some(ty) = obj.get("type") {
+            let types = match ty {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                ty => vec![ty.as_str().unwrap_or_default()],
+            };
+            let matches = |ty: &str| match (ty, value) {
+                ("string", Value::String(_)) | ("boolean", Value::Bool(_)) => true,
+                ("null", Value::Null) | ("array", Value::Array(_)) => true,
+                ("object", Value::Object(_)) | ("number", Value::Number(_)) => true,
+                ("integer", Value::Number(n)) => n.is_i64() || n.is_u64(),
+                _ => false,
+            };
+            if !types.into_iter().any(matches) {
+                return wrong(&format!("is not {}", ty));
+            }
+        }
+        if let Value::Object(fields) = value {
+            let properties = obj.get("properties").and_then(Value::as_object);
+            for name in obj
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                let name = name.as_str().unwrap_or_default();
+                if !fields.contains_key(name) {
+                    return Err(format!("{}.{} is missing", path, name));
+                }
+            }
+            for (name, v) in fields {
+                match properties.and_then(|p| p.get(name)) {
+                    Some(prop) => check(prop, v, defs, &format!("{}.{}", path, name))?,
+                    None if obj.get("additionalProperties") == Some(&json!(false)) => {
+                        return Err(format!("{}.{} is not in the schema", path, name));
+                    }
+                    None => {}
+                }
+            }
+        }
+        if let (Some(items), Value::Array(values)) = (obj.get("items"), value) {
+            for (i, v) in values.iter().enumerate() {
+                check(items, v, defs, &format!("{}[{}]", path, i))?;
+            }
+        }
+        if let Some(Value::Array(schemas)) = obj.get("allOf") {
+            for schema in schemas {
+                check(schema, value, defs, path)?;
+            }
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(schemas)) = obj.get(key) {
+                let passed = schemas
+                    .iter()
+                    .filter(|s| check(s, value, defs, path).is_ok())
+                    .count();
+                if passed == 0 || (key == "oneOf" && passed > 1) {
+                    return wrong(&format!("passes {} of its {}", passed, key));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deka() -> DekaInfo {
+        DekaInfo {
+            deka_no: "264/2567".to_string(),
+            short_note: "short".to_string(),
+            long_note: None,
+            metadata: DekaMetadata {
+                law: "law".to_string(),
+                source: "source".to_string(),
+                url: Some("https://example.com".to_string()),
+            },
+        }
+    }
+
+    fn requests() -> Vec<Value> {
+        let mut full = fixture::payload(1);
+        full.job_id = Some("job".to_string());
+        full.protocol_version = Some(1);
+        full.stream = true;
+        full.parse_mode = Some(ParseMode::Html);
+        let search = MessagePayload {
+            info: TGDeka::Search(TGDekaSearch {
+                search_words: vec!["word".to_string()],
+                search_law: Some("law".to_string()),
+                search_law_no: None,
+                case_from: Some(2560),
+                case_to: None,
+                with_long_note: true,
+            }),
+            ..fixture::payload(2)
+        };
+
+        let mut reqs = [fixture::payload(0), full, search]
+            .iter()
+            .map(|pld| serde_json::to_value(pld).unwrap())
+            .collect::<Vec<_>>();
+        reqs.push(json!({"cancel": {"job_id": "job"}}));
+        reqs.push(json!({"cancel": {"update_id": 1}}));
+        reqs
+    }
+
+    fn responses() -> Vec<TGResponse> {
+        let message = fixture::message(1);
+        let job_id = Some("job".to_string());
+        let mut resps = vec![
+            TGResponse::Okay(TGResponseOkay::new(message.clone(), None, None)),
+            TGResponse::Okay(
+                TGResponseOkay::new(message.clone(), job_id.clone(), Some(vec![deka()]))
+                    .rendered(Some(ParseMode::MarkdownV2)),
+            ),
+            TGResponse::Err(TGResponseErr {
+                reason: Some(TGErrorReason::InvalidRequest),
+                details: Some("details".to_string()),
+                ..TGResponseErr::new(message.clone(), job_id.clone(), TGErrorCode::InvalidQuery)
+            }),
+            TGResponse::Hello(TGHello {
+                protocol_version: 1,
+                worker_id: "worker".to_string(),
+                worker_version: "0.1.0".to_string(),
+                modes: vec!["number".to_string()],
+                sources: vec!["spc".to_string()],
+                max_concurrency: 2,
+            }),
+            TGResponse::Partial(TGResponsePartial {
+                from: "deka".to_string(),
+                message: message.clone(),
+                job_id: "job".to_string(),
+                seq: 1,
+                source: "spc".to_string(),
+                result: vec![deka()],
+                labels: Some(crate::i18n::Labels::new(Lang::Th)),
+                parse_mode: Some(ParseMode::Html),
+                parts: vec!["part".to_string()],
+            }),
+        ];
+
+        let states = [
+            TGJobState::Accepted { queue_position: 1 },
+            TGJobState::Running {
+                source: "spc".to_string(),
+            },
+            TGJobState::Progress {
+                step: TGJobStep::Searching,
+            },
+            TGJobState::Cancelled {
+                superseded_by: Some("other".to_string()),
+            },
+        ];
+        for state in states {
+            // Fails to build when a state is added, so it gets a sample here
+            match state {
+                TGJobState::Accepted { .. }
+                | TGJobState::Running { .. }
+                | TGJobState::Progress { .. }
+                | TGJobState::Cancelled { .. } => {}
+            }
+            resps.push(TGResponse::Status(TGJobStatus::new(
+                message.clone(),
+                "job".to_string(),
+                state,
+            )));
+        }
+
+        resps
+    }
+
+    #[test]
+    fn schema_matches_model() {
+        let defs = defs();
+        let root = |name: &str| json!({"$ref": format!("#/$defs/{}", name)});
+
+        for req in requests() {
+            check(&root("TGRequest"), &req, &defs, "TGRequest").unwrap();
+        }
+        for resp in responses() {
+            let value = serde_json::to_value(&resp).unwrap();
+            check(&root("TGResponse"), &value, &defs, "TGResponse").unwrap();
+        }
+
+        let updates =
+            std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/updates")).unwrap();
+        for entry in updates {
+            let txt = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            let update =
+                serde_json::to_value(serde_json::from_str::<TGMessgae>(&txt).unwrap()).unwrap();
+            check(&root("TGMessgae"), &update, &defs, "TGMessgae").unwrap();
+        }
+
+        // A mode the model does not have is caught
+        let mut bad = serde_json::to_value(fixture::payload(1)).unwrap();
+        bad["info"]["mode"] = "other".into();
+        assert!(check(&root("MessagePayload"), &bad, &defs, "").is_err());
+    }
+
+    #[test]
+    fn committed_files_are_fresh() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(DIR);
+        let hint = format!("out of date, run `cargo run -- --write-schema {}`", DIR);
+
+        let json = std::fs::read_to_string(dir.join(JSON_FILE)).unwrap_or_default();
+        assert!(json == json_schema(), "{} {}", JSON_FILE, hint);
+        let ts = std::fs::read_to_string(dir.join(TS_FILE)).unwrap_or_default();
+        assert!(ts == typescript(), "{} {}", TS_FILE, hint);
+    }
+}
@@ -1 +1,12 @@
+pub mod bot;
+pub mod browser;
+pub mod dead_letter;
 pub mod deka;
+pub mod executor;
+pub mod health;
+pub mod http;
+pub mod job;
+pub mod metrics;
+pub mod spool;
+pub mod systemd;
+pub mod webdriver;
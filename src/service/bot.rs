@@ -0,0 +1,266 @@
+use std::time::{Duration, Instant};
+
+use async_tungstenite::{
+    tokio::{connect_async, ConnectStream},
+    tungstenite::{handshake::client, http::Request, Message},
+    WebSocketStream,
+};
+use futures::{stream::SplitSink, SinkExt};
+use rand::Rng;
+use snafu::ResultExt;
+
+use crate::{error, model::TGResponse, util};
+
+pub type WSStream = WebSocketStream<ConnectStream>;
+pub type WSWrite = SplitSink<WSStream, Message>;
+
+/// Jittered exponential backoff for reconnecting to the bot.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    max_retries: u32,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60), 30)
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration, max_retries: u32) -> Self {
+        Self {
+            base,
+            max,
+            max_retries,
+            attempt: 0,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay before the next attempt, or `None` once `max_retries` is used up.
+    /// Half of the exponential step is fixed and the other half is random.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.max_retries {
+            return None;
+        }
+
+        let exp = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+
+        let half = exp / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+
+        Some(half + Duration::from_millis(jitter))
+    }
+}
+
+/// Keepalive for the bot link: pings on an interval and flags the
+/// connection dead when a ping goes unanswered for longer than `timeout`.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    seq: u64,
+    pending: Option<(u64, Instant)>,
+    latency: Option<Duration>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(20), Duration::from_secs(10))
+    }
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            seq: 0,
+            pending: None,
+            latency: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Round trip of the last answered ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Payload for the next ping. An unanswered ping stays the one we time.
+    pub fn ping(&mut self, now: Instant) -> Vec<u8> {
+        self.seq += 1;
+
+        if self.pending.is_none() {
+            self.pending = Some((self.seq, now));
+        }
+
+        self.seq.to_be_bytes().to_vec()
+    }
+
+    pub fn on_pong(&mut self, payload: &[u8], now: Instant) {
+        let Ok(seq) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+
+        if let Some((pending_seq, sent_at)) = self.pending {
+            if seq >= pending_seq {
+                self.latency = Some(now.saturating_duration_since(sent_at));
+                self.pending = None;
+            }
+        }
+    }
+
+    /// When the link counts as dead unless the pending ping is answered.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, sent_at)| sent_at + self.timeout)
+    }
+
+    pub fn is_dead(&self, now: Instant) -> bool {
+        self.pending
+            .map(|(_, sent_at)| now.saturating_duration_since(sent_at) > self.timeout)
+            .unwrap_or(false)
+    }
+}
+
+fn ws_request(ws_addr: &str, token: &str) -> util::Result<client::Request> {
+    let ws_req = Request::builder()
+        .uri(format!("wss://{}/ws", ws_addr))
+        .method("GET")
+        .header("Host", ws_addr)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", client::generate_key())
+        .header("Authorization", format!("Bearer {}", token))
+        .body(())
+        .context(error::HTTPSnafu)?;
+
+    Ok(client::Request::from(ws_req))
+}
+
+pub async fn connect(ws_addr: &str, token: &str) -> util::Result<WSStream> {
+    let (ws_strm, _) = connect_async(ws_request(ws_addr, token)?)
+        .await
+        .context(error::WSSnafu)?;
+
+    Ok(ws_strm)
+}
+
+/// Keep dialing the bot until it answers or the backoff gives up. The
+/// caller resets the backoff once the link answers a heartbeat, so a link
+/// that drops right after opening keeps backing off.
+pub async fn connect_with_backoff(
+    ws_addr: &str,
+    token: &str,
+    backoff: &mut Backoff,
+) -> util::Result<WSStream> {
+    loop {
+        match connect(ws_addr, token).await {
+            Ok(ws_strm) => return Ok(ws_strm),
+            Err(e) => match backoff.next_delay() {
+                Some(delay) => {
+                    tracing::warn!(
+                        "bot::connect | Attempt {} failed, retry in {:?}: {:?}",
+                        backoff.attempt(),
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    tracing::error!("bot::connect | Giving up: {:?}", e);
+                    return error::ReconnectSnafu.fail();
+                }
+            },
+        }
+    }
+}
+
+pub async fn send(ws_write: &mut WSWrite, tg_msg: &TGResponse) -> util::Result<()> {
+    let ws_msg = serde_json::to_string(tg_msg).context(error::SerdeJsonSnafu)?;
+
+    ws_write
+        .send(Message::Text(ws_msg))
+        .await
+        .context(error::WSSnafu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000), 10);
+        let delays = (0..6)
+            .map(|_| backoff.next_delay().unwrap())
+            .collect::<Vec<_>>();
+
+        for (i, delay) in delays.iter().enumerate() {
+            let exp =
+                Duration::from_millis(100 * 2u64.pow(i as u32)).min(Duration::from_millis(1000));
+            assert!(*delay >= exp / 2, "attempt {} delay {:?}", i, delay);
+            assert!(*delay <= exp, "attempt {} delay {:?}", i, delay);
+        }
+    }
+
+    #[test]
+    fn backoff_gives_up_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(4), 2);
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay().is_some());
+    }
+
+    #[test]
+    fn heartbeat_tracks_latency() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(20), Duration::from_secs(10));
+        let now = Instant::now();
+        let payload = heartbeat.ping(now);
+
+        assert!(!heartbeat.is_dead(now + Duration::from_secs(5)));
+        assert_eq!(heartbeat.deadline(), Some(now + Duration::from_secs(10)));
+        heartbeat.on_pong(&payload, now + Duration::from_millis(150));
+        assert_eq!(heartbeat.latency(), Some(Duration::from_millis(150)));
+        assert!(heartbeat.deadline().is_none());
+        assert!(!heartbeat.is_dead(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn heartbeat_missed_pong_is_dead() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(20), Duration::from_secs(10));
+        let now = Instant::now();
+        let first = heartbeat.ping(now);
+        heartbeat.ping(now + Duration::from_secs(20));
+
+        // Foreign pong payloads are ignored
+        heartbeat.on_pong(b"hello", now + Duration::from_secs(21));
+        assert!(heartbeat.is_dead(now + Duration::from_secs(21)));
+
+        // A late pong for the first ping still revives the link
+        heartbeat.on_pong(&first, now + Duration::from_secs(22));
+        assert!(!heartbeat.is_dead(now + Duration::from_secs(22)));
+    }
+}
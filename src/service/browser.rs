@@ -0,0 +1,568 @@
+use std::{
+    ops::Deref,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use fantoccini::{wd::Capabilities, Client, ClientBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{OptionExt, ResultExt};
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+
+use crate::{
+    error,
+    service::{health::HEALTH, metrics::METRICS},
+    util,
+};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserKind {
+    #[default]
+    Firefox,
+    /// Chrome or Chromium behind chromedriver
+    Chromium,
+}
+
+impl FromStr for BrowserKind {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> util::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "firefox" => Ok(Self::Firefox),
+            "chromium" | "chrome" => Ok(Self::Chromium),
+            _ => error::BrowserKindSnafu { kind: s }.fail(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct WindowSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for WindowSize {
+    type Err = error::Error;
+
+    /// Parse `WIDTHxHEIGHT`, e.g. `1920x1080`.
+    fn from_str(s: &str) -> util::Result<Self> {
+        let (width, height) = s
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+            .context(error::WindowSizeSnafu { size: s })?;
+
+        Ok(Self { width, height })
+    }
+}
+
+/// How to reach the WebDriver and what browser session to ask it for.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrowserConfig {
+    pub webdriver_url: String,
+    /// Run this WebDriver binary ourselves instead of using `webdriver_url`
+    pub webdriver_path: Option<PathBuf>,
+    pub browser: BrowserKind,
+    pub headless: bool,
+    pub window_size: Option<WindowSize>,
+    pub user_agent: Option<String>,
+    /// Merged over the generated capabilities, so it can override any of them.
+    pub extra_capabilities: Capabilities,
+    /// Sessions kept open. geckodriver serves only one at a time.
+    pub pool_size: usize,
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        Self {
+            webdriver_url: "http://localhost:4444".to_string(),
+            webdriver_path: None,
+            browser: BrowserKind::default(),
+            headless: true,
+            window_size: None,
+            user_agent: None,
+            extra_capabilities: Capabilities::new(),
+            pool_size: 1,
+        }
+    }
+}
+
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (k, v) in over {
+                merge(base.entry(k).or_insert(Value::Null), v);
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+impl BrowserConfig {
+    pub fn capabilities(&self) -> Capabilities {
+        let mut args: Vec<String> = Vec::new();
+
+        let mut cap = match self.browser {
+            BrowserKind::Firefox => {
+                let mut prefs = serde_json::Map::new();
+
+                if self.headless {
+                    args.push("--headless".to_string());
+                }
+                if let Some(size) = self.window_size {
+                    args.push(format!("--width={}", size.width));
+                    args.push(format!("--height={}", size.height));
+                }
+                if let Some(ua) = &self.user_agent {
+                    prefs.insert("general.useragent.override".to_string(), json!(ua));
+                }
+
+                json!({
+                    "browserName": "firefox",
+                    "moz:firefoxOptions": { "args": args, "prefs": prefs },
+                })
+            }
+            BrowserKind::Chromium => {
+                if self.headless {
+                    args.push("--headless=new".to_string());
+                }
+                if let Some(size) = self.window_size {
+                    args.push(format!("--window-size={},{}", size.width, size.height));
+                }
+                if let Some(ua) = &self.user_agent {
+                    args.push(format!("--user-agent={}", ua));
+                }
+
+                json!({
+                    "browserName": "chrome",
+                    "goog:chromeOptions": { "args": args },
+                })
+            }
+        };
+
+        merge(&mut cap, Value::Object(self.extra_capabilities.clone()));
+
+        match cap {
+            Value::Object(cap) => cap,
+            _ => Capabilities::new(),
+        }
+    }
+
+    /// Open a new WebDriver session.
+    pub async fn connect(&self) -> util::Result<Client> {
+        ClientBuilder::native()
+            .capabilities(self.capabilities())
+            .connect(&self.webdriver_url)
+            .await
+            .context(error::FantocciniSessionSnafu)
+    }
+}
+
+/// A fixed number of WebDriver sessions shared by the lookups.
+/// Dead sessions are thrown away and replaced on the next `acquire`.
+pub struct BrowserPool {
+    config: BrowserConfig,
+    warm_url: String,
+    size: usize,
+    idle: Mutex<Vec<Client>>,
+    /// Sessions open or being opened, idle or not, never more than `size`
+    open: AtomicUsize,
+    /// Sessions that came up and were not thrown away yet
+    live: AtomicUsize,
+    /// Woken when a session goes idle or a slot frees up
+    changed: Notify,
+    permits: Semaphore,
+    restarts: AtomicU64,
+}
+
+/// A session borrowed from the pool. It goes back to the pool on drop,
+/// unless `check` saw the session die.
+pub struct Session<'a> {
+    pool: &'a BrowserPool,
+    client: Option<Client>,
+    healthy: bool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl BrowserPool {
+    pub fn new(config: BrowserConfig, warm_url: impl Into<String>) -> Self {
+        let size = config.pool_size.max(1);
+        if config.browser == BrowserKind::Firefox && size > 1 {
+            tracing::warn!(
+                "browser::new | geckodriver serves one session, {} of the {} will fail",
+                size - 1,
+                size
+            );
+        }
+
+        Self {
+            config,
+            warm_url: warm_url.into(),
+            size,
+            idle: Mutex::new(Vec::new()),
+            open: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            changed: Notify::new(),
+            permits: Semaphore::new(size),
+            restarts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Sessions open or being opened.
+    pub fn sessions(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Sessions thrown away because they died.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self) -> bool {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.size).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+        self.changed.notify_waiters();
+    }
+
+    /// Count a session that came up or went away, and report whether any
+    /// is left as browser readiness.
+    fn set_live(&self, up: bool) {
+        let live = if up {
+            self.live.fetch_add(1, Ordering::AcqRel) + 1
+        } else {
+            self.live.fetch_sub(1, Ordering::AcqRel) - 1
+        };
+        HEALTH.set_browser_ready(live > 0);
+    }
+
+    /// Open a session if the pool has room for one.
+    async fn open(&self) -> util::Result<Option<Client>> {
+        if !self.reserve() {
+            return Ok(None);
+        }
+
+        match self.connect().await {
+            Ok(client) => {
+                self.set_live(true);
+                Ok(Some(client))
+            }
+            Err(e) => {
+                self.release();
+                Err(e)
+            }
+        }
+    }
+
+    async fn connect(&self) -> util::Result<Client> {
+        let client = self.config.connect().await?;
+        client.persist().await.context(error::FantocciniCmdSnafu)?;
+        client
+            .goto(&self.warm_url)
+            .await
+            .context(error::FantocciniCmdSnafu)?;
+
+        Ok(client)
+    }
+
+    async fn is_alive(client: &Client) -> bool {
+        client.current_url().await.is_ok()
+    }
+
+    /// Close the windows a cancelled lookup left open and go back to the
+    /// first one. Fails when the session is dead.
+    async fn reset(client: &Client) -> util::Result<()> {
+        let windows = client.windows().await.context(error::FantocciniCmdSnafu)?;
+        let (first, extra) = windows.split_first().context(error::BrowserNoWindowSnafu)?;
+
+        for window in extra {
+            client
+                .switch_to_window(window.clone())
+                .await
+                .context(error::FantocciniCmdSnafu)?;
+            client
+                .close_window()
+                .await
+                .context(error::FantocciniCmdSnafu)?;
+        }
+        client
+            .switch_to_window(first.clone())
+            .await
+            .context(error::FantocciniCmdSnafu)
+    }
+
+    fn discard(&self, client: Client) {
+        self.release();
+        self.set_live(false);
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+        METRICS.session_restarts.inc();
+        tokio::spawn(async move {
+            let _ = client.close().await;
+        });
+    }
+
+    fn take_idle(&self) -> Vec<Client> {
+        std::mem::take(&mut *self.idle.lock().unwrap())
+    }
+
+    fn put_idle(&self, client: Client) {
+        self.idle.lock().unwrap().push(client);
+        self.changed.notify_waiters();
+    }
+
+    /// Open every session up front so the first lookups do not pay for it.
+    pub async fn warm_up(&self) -> util::Result<()> {
+        for i in 0..self.size {
+            match self.open().await {
+                Ok(Some(client)) => self.put_idle(client),
+                Ok(None) => break,
+                // Not even one, `acquire` will keep trying on demand
+                Err(e) if i == 0 => return Err(e),
+                Err(e) => {
+                    tracing::warn!("browser::warm_up | Session {} failed: {:?}", i, e);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn acquire(&self) -> util::Result<Session<'_>> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .context(error::BrowserPoolSnafu)?;
+
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let idle = self.idle.lock().unwrap().pop();
+
+            let client = match idle {
+                Some(client) => match Self::reset(&client).await {
+                    Ok(_) => client,
+                    Err(e) => {
+                        tracing::warn!("browser::acquire | Dropping dead session: {:?}", e);
+                        self.discard(client);
+                        continue;
+                    }
+                },
+                None => match self.open().await? {
+                    Some(client) => client,
+                    // Every session is in use or being checked, wait for one
+                    None => {
+                        changed.await;
+                        continue;
+                    }
+                },
+            };
+
+            return Ok(Session {
+                pool: self,
+                client: Some(client),
+                healthy: true,
+                _permit: permit,
+            });
+        }
+    }
+
+    /// Drop dead idle sessions and top the pool back up.
+    pub async fn health_check(&self) {
+        let mut alive = Vec::new();
+
+        for client in self.take_idle() {
+            if Self::is_alive(&client).await {
+                alive.push(client);
+            } else {
+                tracing::warn!("browser::health_check | Dropping dead session");
+                self.discard(client);
+            }
+        }
+
+        // Leased sessions count too, so the pool never grows past `size`
+        loop {
+            match self.open().await {
+                Ok(Some(client)) => alive.push(client),
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("browser::health_check | Reconnect failed: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        self.idle.lock().unwrap().extend(alive);
+        self.changed.notify_waiters();
+    }
+
+    pub async fn close(&self) {
+        HEALTH.set_browser_ready(false);
+        for client in self.take_idle() {
+            self.release();
+            self.live.fetch_sub(1, Ordering::AcqRel);
+            let _ = client.close().await;
+        }
+    }
+}
+
+impl Session<'_> {
+    /// Note the outcome of a command run on this session. Returns `false`
+    /// when the session died, in which case it is not returned to the pool.
+    pub fn check<T>(&mut self, res: &util::Result<T>) -> bool {
+        if let Err(e) = res {
+            if e.is_dead_session() {
+                tracing::warn!("browser::check | Session died: {:?}", e);
+                self.healthy = false;
+            }
+        }
+
+        self.healthy
+    }
+}
+
+impl Deref for Session<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if self.healthy {
+                self.pool.put_idle(client);
+            } else {
+                self.pool.discard(client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fantoccini::error::CmdError;
+
+    use super::*;
+
+    #[test]
+    fn lost_connection_is_dead_session() {
+        let lost = error::Error::FantocciniCmdError {
+            source: CmdError::Lost(std::io::ErrorKind::ConnectionReset.into()),
+        };
+        let timeout = error::Error::FantocciniCmdError {
+            source: CmdError::WaitTimeout,
+        };
+
+        assert!(lost.is_dead_session());
+        assert!(!timeout.is_dead_session());
+    }
+
+    #[tokio::test]
+    async fn acquire_without_webdriver_fails() {
+        let pool = BrowserPool::new(
+            BrowserConfig {
+                webdriver_url: "http://127.0.0.1:9".to_string(),
+                pool_size: 1,
+                ..Default::default()
+            },
+            "about:blank",
+        );
+
+        assert!(pool.warm_up().await.is_err());
+        assert!(pool.acquire().await.is_err());
+        // The failed attempt must hand its permit and slot back
+        assert_eq!(pool.permits.available_permits(), 1);
+        assert_eq!(pool.sessions(), 0);
+        pool.health_check().await;
+        assert_eq!(pool.sessions(), 0);
+    }
+
+    #[test]
+    fn slots_are_bounded() {
+        let pool = BrowserPool::new(
+            BrowserConfig {
+                browser: BrowserKind::Chromium,
+                pool_size: 2,
+                ..Default::default()
+            },
+            "about:blank",
+        );
+
+        assert!(pool.reserve() && pool.reserve());
+        assert!(!pool.reserve());
+        pool.release();
+        assert_eq!(pool.sessions(), 1);
+        assert!(pool.reserve());
+    }
+
+    #[test]
+    fn firefox_capabilities() {
+        let config = BrowserConfig {
+            window_size: Some("1920x1080".parse().unwrap()),
+            user_agent: Some("deka-bot".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Value::Object(config.capabilities()),
+            json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": {
+                    "args": ["--headless", "--width=1920", "--height=1080"],
+                    "prefs": { "general.useragent.override": "deka-bot" },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn chromium_capabilities_with_extra() {
+        let config = BrowserConfig {
+            browser: "chrome".parse().unwrap(),
+            headless: false,
+            extra_capabilities: serde_json::from_str(
+                r#"{"goog:chromeOptions":{"binary":"/usr/bin/chromium"},"acceptInsecureCerts":true}"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Value::Object(config.capabilities()),
+            json!({
+                "browserName": "chrome",
+                "acceptInsecureCerts": true,
+                "goog:chromeOptions": { "args": [], "binary": "/usr/bin/chromium" },
+            })
+        );
+    }
+
+    #[test]
+    fn bad_window_size() {
+        assert!("1920".parse::<WindowSize>().is_err());
+        assert!("axb".parse::<WindowSize>().is_err());
+        assert!("safari".parse::<BrowserKind>().is_err());
+    }
+}
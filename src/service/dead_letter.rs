@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error,
+    service::{metrics::METRICS, spool::unix_now},
+    util,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+struct DeadLetter {
+    at: u64,
+    error: String,
+    payload: String,
+}
+
+/// Requests that could not be parsed far enough to be answered. They are
+/// counted, and appended to a JSONL file when `path` is set.
+#[derive(Debug, Default)]
+pub struct DeadLetters {
+    path: Option<PathBuf>,
+}
+
+impl DeadLetters {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+
+    pub async fn record(&self, payload: &str, error: &serde_json::Error) -> util::Result<()> {
+        METRICS.rejected("unparseable");
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_string(&DeadLetter {
+            at: unix_now()?,
+            error: error.to_string(),
+            payload: payload.to_string(),
+        })
+        .context(error::SerdeJsonSnafu)?;
+        line.push('\n');
+
+        let mut fs = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .context(error::IOSnafu)?;
+        fs.write_all(line.as_bytes())
+            .await
+            .context(error::IOSnafu)?;
+        fs.flush().await.context(error::IOSnafu)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_appends_lines() {
+        let path = std::env::temp_dir().join(format!("dead-letter-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let dead_letters = DeadLetters::new(Some(path.clone()));
+
+        for txt in ["not json", r#"{"info":{}}"#] {
+            let e = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+            dead_letters.record(txt, &e).await.unwrap();
+        }
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let letters = lines
+            .lines()
+            .map(|l| serde_json::from_str::<DeadLetter>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].payload, "not json");
+        assert_eq!(letters[1].payload, r#"{"info":{}}"#);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,49 +1,138 @@
 use std::{
+    collections::HashMap,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    error,
+    error, i18n,
     model::{
-        DekaInfo, DekaMetadata, MessagePayload, TGDeka, TGDekaNumber, TGDekaSearch, TGResponse,
-        TGResponseErr, TGResponseOkay,
+        DekaInfo, DekaMetadata, MessagePayload, RejectedPayload, TGDeka, TGDekaNumber,
+        TGDekaSearch, TGErrorCode, TGErrorReason, TGHello, TGJobState, TGJobStatus, TGJobStep,
+        TGRequest, TGResponse, TGResponseErr, TGResponseOkay, PROTOCOL_VERSION,
+    },
+    service::{
+        browser::{BrowserConfig, BrowserPool},
+        executor::Executor,
+        health::HEALTH,
+        job::{self, Job},
+        metrics::METRICS,
     },
     util,
 };
-use fantoccini::{elements::Form, Client, ClientBuilder, Locator};
+use fantoccini::{elements::Form, Client, Locator};
 use futures::future::join_all;
 use itertools::Itertools;
 use regex::Regex;
 use reqwest;
 use scraper::{Html, Selector};
-use snafu::{OptionExt, ResultExt};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, Report, ResultExt};
 use tokio::{
     io::AsyncWriteExt,
-    sync::{broadcast, mpsc, Mutex},
+    sync::{broadcast, mpsc, watch},
+    time::{interval_at, sleep_until, Instant},
 };
 
+use tracing::Instrument;
 use url::Url;
 
-async fn dekasuksa_deka_exec(q: String) -> util::Result<Option<Vec<DekaInfo>>> {
-    let mut url = Url::parse("https://www.dekasuksa.com/search").context(error::URLSnafu)?;
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Dekasuksa,
+    /// deka.supremecourt.or.th, through the browser pool
+    Spc,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dekasuksa => "dekasuksa",
+            Self::Spc => "spc",
+        }
+    }
+}
+
+/// Where and how the scrapers look things up.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DekaConfig {
+    pub dekasuksa_url: String,
+    pub spc_url: String,
+    /// Wait for SPC result and print pages
+    pub page_timeout_secs: u64,
+    /// Wait for SPC form widgets
+    pub element_timeout_secs: u64,
+    /// Save SPC screenshots here when set
+    pub screenshot_dir: Option<PathBuf>,
+    pub max_concurrency: usize,
+    pub shutdown_grace_secs: u64,
+    /// Tried in order until one of them answers
+    pub sources: Vec<Source>,
+    /// Cancel a sender's running lookups when they send a new one
+    pub supersede: bool,
+}
+
+impl Default for DekaConfig {
+    fn default() -> Self {
+        Self {
+            dekasuksa_url: "https://www.dekasuksa.com/search".to_string(),
+            spc_url: SPC_URL.to_string(),
+            page_timeout_secs: 30,
+            element_timeout_secs: 10,
+            screenshot_dir: None,
+            max_concurrency: 4,
+            shutdown_grace_secs: 30,
+            sources: vec![Source::Dekasuksa, Source::Spc],
+            supersede: false,
+        }
+    }
+}
+
+impl DekaConfig {
+    pub fn page_timeout(&self) -> Duration {
+        Duration::from_secs(self.page_timeout_secs)
+    }
+
+    pub fn element_timeout(&self) -> Duration {
+        Duration::from_secs(self.element_timeout_secs)
+    }
+
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+// The query is user text, keep it out of the span and at debug level
+#[tracing::instrument(skip(config, q))]
+async fn dekasuksa_deka_exec(
+    config: &DekaConfig,
+    q: String,
+) -> util::Result<Option<Vec<DekaInfo>>> {
+    tracing::debug!("deka::dekasuksa_deka_exec | Query {:?}", q);
+    let _timer = METRICS
+        .latency
+        .with_label_values(&["dekasuksa_deka_exec"])
+        .start_timer();
+    let mut url = Url::parse(&config.dekasuksa_url).context(error::URLSnafu)?;
     url.query_pairs_mut().append_pair("q", &q);
 
     // Query from the web
     let html = reqwest::get(url.to_string())
         .await
+        .and_then(|resp| resp.error_for_status())
         .context(error::ReqwestSnafu)?
         .text()
         .await
         .context(error::ReqwestSnafu)?;
     // Selectors
-    let (slct_blog_post, slct_title_a, slct_title, slct_cntn) = match (
+    let (slct_blog_post, slct_title_a) = match (
         Selector::parse(r#".blog-posts .blog-post"#),
         Selector::parse(r#".post-title a"#),
-        Selector::parse(r#"h1.post-title"#),
-        Selector::parse(r#".post-body.post-content"#),
     ) {
-        (Ok(s1), Ok(s2), Ok(s3), Ok(s4)) => (s1, s2, s3, s4),
+        (Ok(s1), Ok(s2)) => (s1, s2),
         _ => return Ok(None),
     };
 
@@ -51,115 +140,41 @@ async fn dekasuksa_deka_exec(q: String) -> util::Result<Option<Vec<DekaInfo>>> {
 
     let deka_links = document
         .select(&slct_blog_post)
-        .map(|deka| {
-            deka.select(&&slct_title_a)
+        .filter_map(|deka| {
+            deka.select(&slct_title_a)
                 .next()?
                 .value()
                 .attr("href")
-                .and_then(|l| Some(l.to_string()))
+                .map(|l| l.to_string())
         })
-        .flatten()
         .collect::<Vec<String>>();
 
     if deka_links.is_empty() {
         return Ok(None);
     }
 
-    let deka_resp = join_all(
-        deka_links
-            .iter()
-            .map(|link| reqwest::get(link))
-            .collect_vec(),
-    )
-    .await
-    .into_iter()
-    .flatten()
-    .filter(|res| res.status().as_u16() == 200)
-    .map(|res| res.text())
-    .collect_vec();
+    let deka_resp = join_all(deka_links.iter().map(reqwest::get).collect_vec())
+        .await
+        .into_iter()
+        .flatten()
+        .filter(|res| res.status().as_u16() == 200)
+        .map(|res| res.text())
+        .collect_vec();
 
     let mut deka_res = join_all(deka_resp)
         .await
         .into_iter()
         .map(|txt| {
-            txt.context(error::ReqwestSnafu).and_then(|t| {
-                let doc = Html::parse_document(t.as_str());
-                let post_header = doc
-                    .select(&slct_title)
-                    .next()
-                    .context(error::EmptySnafu)?
-                    .text()
-                    .collect::<Vec<_>>()
-                    .concat();
-                let post_content = doc
-                    .select(&slct_cntn)
-                    .next()
-                    .context(error::EmptySnafu)?
-                    .text()
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                let mut meta_law: Option<String> = None;
-                let mut short_note = String::new();
-                let mut long_note: Option<String> = None;
-
-                for txt_line in post_content.lines() {
-                    let txt_stp = txt_line.trim();
-
-                    if meta_law.is_none() {
-                        if long_note.is_none() {
-                            if txt_stp == "เพิ่มเติม" {
-                                long_note = Some(String::new());
-                            } else {
-                                short_note.push_str(&(txt_stp.to_string() + "\n"));
-                            }
-                        } else if txt_stp.is_empty() && meta_law.is_none() {
-                            meta_law = Some(String::new());
-                        } else if txt_stp.len() < 100 && !short_note.is_empty() {
-                            meta_law
-                                .as_mut()
-                                .and_then(|ml| Some(ml.to_owned() + txt_stp + "\n"));
-                        } else if long_note.is_some() {
-                            long_note
-                                .as_mut()
-                                .and_then(|ln| Some(ln.to_owned() + txt_stp + "\n"));
-                        }
-                    } else {
-                        meta_law
-                            .as_mut()
-                            .and_then(|ml| Some(ml.to_owned() + txt_stp + "\n"));
-                    }
-                }
-
-                Ok(DekaInfo {
-                    deka_no: post_header
-                        .replace("๐", "0")
-                        .replace("๑", "1")
-                        .replace("๒", "2")
-                        .replace("๓", "3")
-                        .replace("๔", "4")
-                        .replace("๕", "5")
-                        .replace("๖", "6")
-                        .replace("๗", "7")
-                        .replace("๘", "8")
-                        .replace("๙", "9"),
-                    short_note: short_note,
-                    long_note,
-                    metadata: DekaMetadata {
-                        law: meta_law.unwrap_or_default(),
-                        source: "เว็บไซต์ฎีกาศึกษา".to_string(),
-                    },
-                })
-            })
+            txt.context(error::ReqwestSnafu)
+                .and_then(|t| dekasuksa_post(&t))
         })
         .collect_vec();
 
-    for (i, dl) in deka_links.iter().enumerate() {
-        deka_res.get_mut(i).and_then(|dr| {
-            Some(dr.as_mut().and_then(|dri| {
-                dri.metadata.source.push_str(format!(" {}", dl).as_str());
-                Ok(dri)
-            }))
-        });
+    for (dr, dl) in deka_res.iter_mut().zip(deka_links.iter()) {
+        if let Ok(dri) = dr {
+            dri.metadata.source.push_str(format!(" {}", dl).as_str());
+            dri.metadata.url = Some(dl.to_string());
+        }
     }
 
     let deka_res_final = deka_res.into_iter().flatten().collect_vec();
@@ -218,30 +233,133 @@ async fn spc_text(client: &Client, selector: &str) -> util::Result<String> {
 }
 
 // Supreme courts
-async fn spc_deka_init(client: &Client) -> util::Result<()> {
+pub const SPC_URL: &str = "http://deka.supremecourt.or.th/";
+
+async fn spc_deka_init(client: &Client, config: &DekaConfig) -> util::Result<()> {
     client
-        .goto("http://deka.supremecourt.or.th/")
+        .goto(&config.spc_url)
         .await
         .context(error::FantocciniCmdSnafu)?;
 
     Ok(())
 }
 
+/// Lines of a dekasuksa post below its short note: the long note after
+/// "เพิ่มเติม", then the law after a blank line. Only short lines are
+/// taken as law, a long paragraph stays with the long note.
+fn dekasuksa_notes(post_content: &str) -> (String, Option<String>, Option<String>) {
+    const LAW_MAX_CHARS: usize = 100;
+
+    let mut short_note = String::new();
+    let mut long_note: Option<String> = None;
+    let mut meta_law: Option<String> = None;
+    let mut after_blank = false;
+
+    for txt_line in post_content.lines() {
+        let txt_stp = txt_line.trim();
+        let is_law = txt_stp.chars().count() < LAW_MAX_CHARS;
+
+        match (long_note.as_mut(), meta_law.as_mut()) {
+            (None, _) if txt_stp == "เพิ่มเติม" => {
+                long_note = Some(String::new())
+            }
+            (None, _) => short_note.push_str(&(txt_stp.to_string() + "\n")),
+            (Some(ln), meta_law) if txt_stp.is_empty() => {
+                // Keep paragraph breaks in the long note
+                if meta_law.is_none() && !ln.is_empty() && !ln.ends_with("\n\n") {
+                    ln.push('\n');
+                }
+                after_blank = true
+            }
+            // The law starts at a short line after a blank one, once
+            // there is some long note
+            (Some(ln), None) if after_blank && is_law && !ln.is_empty() => {
+                meta_law = Some(txt_stp.to_string() + "\n")
+            }
+            (Some(_), Some(ml)) if is_law => ml.push_str(&(txt_stp.to_string() + "\n")),
+            (Some(ln), _) => {
+                after_blank = false;
+                ln.push_str(&(txt_stp.to_string() + "\n"))
+            }
+        }
+    }
+
+    (
+        short_note,
+        long_note
+            .map(|ln| ln.trim().to_string())
+            .filter(|ln| !ln.is_empty()),
+        meta_law.map(|ml| ml.trim().to_string()),
+    )
+}
+
+/// A deka from its dekasuksa post page.
+fn dekasuksa_post(html: &str) -> util::Result<DekaInfo> {
+    let (slct_title, slct_cntn) = match (
+        Selector::parse(r#"h1.post-title"#),
+        Selector::parse(r#".post-body.post-content"#),
+    ) {
+        (Ok(s1), Ok(s2)) => (s1, s2),
+        _ => return error::EmptySnafu.fail(),
+    };
+
+    let doc = Html::parse_document(html);
+    let post_header = doc
+        .select(&slct_title)
+        .next()
+        .context(error::EmptySnafu)?
+        .text()
+        .collect::<Vec<_>>()
+        .concat();
+    let post_content = doc
+        .select(&slct_cntn)
+        .next()
+        .context(error::EmptySnafu)?
+        .text()
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (short_note, long_note, meta_law) = dekasuksa_notes(&post_content);
+
+    Ok(DekaInfo {
+        deka_no: post_header
+            .replace("๐", "0")
+            .replace("๑", "1")
+            .replace("๒", "2")
+            .replace("๓", "3")
+            .replace("๔", "4")
+            .replace("๕", "5")
+            .replace("๖", "6")
+            .replace("๗", "7")
+            .replace("๘", "8")
+            .replace("๙", "9"),
+        short_note,
+        long_note,
+        metadata: DekaMetadata {
+            law: meta_law.unwrap_or_default(),
+            source: i18n::source_name(job::lang(), Source::Dekasuksa.as_str()),
+            url: None,
+        },
+    })
+}
+
+#[tracing::instrument(skip(client, config))]
 async fn spc_deka_exec(
     client: &Client,
+    config: &DekaConfig,
     with_long_note: bool,
 ) -> util::Result<Option<Vec<DekaInfo>>> {
     tracing::info!("spc_deka_exec | Wait Result");
+    job::progress(TGJobStep::Searching);
     client
         .wait()
-        .at_most(core::time::Duration::from_secs(30))
+        .at_most(config.page_timeout())
         .for_url(
             client
                 .current_url()
                 .await
-                .and_then(|mut url| {
+                .map(|mut url| {
                     url.set_path("/search");
-                    Ok(url)
+                    url
                 })
                 .context(error::FantocciniCmdSnafu)?,
         )
@@ -258,14 +376,15 @@ async fn spc_deka_exec(
     tracing::info!("spc_deka_exec | Compiling Result");
 
     if with_long_note {
+        job::progress(TGJobStep::FetchingLongNotes);
         // Tick show long note
-        spc_click(&client, "#btn-show-result-item").await?;
+        spc_click(client, "#btn-show-result-item").await?;
         client
             .wait()
             .for_element(Locator::Id("show_item_long_text"))
             .await
             .context(error::FantocciniCmdSnafu)?;
-        spc_click(&client, r#"label[for="show_item_long_text"]"#).await?;
+        spc_click(client, r#"label[for="show_item_long_text"]"#).await?;
     }
 
     let deka_res_ftr = client
@@ -291,15 +410,15 @@ async fn spc_deka_exec(
                     .and_then(|dkn_mtch| {
                         let txt = dkn_mtch.as_str().trim().to_string();
 
-                        if txt == "" {
+                        if txt.is_empty() {
                             return None;
                         }
 
                         Some(txt)
                     })
-                    .unwrap_or_else(|| dkn_txt),
-                short_note: spc_text(&client, ".item_short_text").await?,
-                long_note: spc_text(&client, ".item_long_text")
+                    .unwrap_or(dkn_txt),
+                short_note: spc_text(client, ".item_short_text").await?,
+                long_note: spc_text(client, ".item_long_text")
                     .await
                     .ok()
                     .and_then(|dkn_mtch| {
@@ -312,11 +431,12 @@ async fn spc_deka_exec(
                         Some(txt)
                     }),
                 metadata: DekaMetadata {
-                    law: spc_text(&client, ".item_law>ul").await?,
-                    source: spc_text(&client, ".item_source>ul").await?,
+                    law: spc_text(client, ".item_law>ul").await?,
+                    source: spc_text(client, ".item_source>ul").await?,
+                    url: None,
                 },
             };
-            println!("deka_info -> {:?}", deka_info);
+            tracing::debug!("deka::spc_deka_exec | Found {:?}", deka_info);
 
             Ok::<DekaInfo, error::Error>(deka_info)
         });
@@ -324,11 +444,7 @@ async fn spc_deka_exec(
     let mut deka_res = join_all(deka_res_ftr)
         .await
         .into_iter()
-        .map(|deka| match deka {
-            Ok(dk) => Some(dk),
-            _ => None,
-        })
-        .flatten()
+        .filter_map(|deka| deka.ok())
         .collect_vec();
 
     if deka_res.is_empty() {
@@ -342,19 +458,23 @@ async fn spc_deka_exec(
         return Ok(Some(deka_res));
     }
 
+    // Long notes take a trip to the print page, send what we have first
+    job::partial(Source::Spc.as_str(), &deka_res);
+
     let mut print_url = client
         .current_url()
         .await
         .context(error::FantocciniCmdSnafu)?;
 
     print_url.set_path("/printing/dekaall");
-    spc_click(&client, "#choose_all_deka").await?;
-    spc_click(&client, "#print_choose_deka").await?;
+    job::progress(TGJobStep::OpeningPrintPage);
+    spc_click(client, "#choose_all_deka").await?;
+    spc_click(client, "#print_choose_deka").await?;
     tracing::info!("deka::spc_deka_exec | Wait Print page");
 
     client
         .wait()
-        .at_most(Duration::from_secs(30))
+        .at_most(config.page_timeout())
         .for_url(print_url)
         .await
         .context(error::FantocciniCmdSnafu)?;
@@ -430,12 +550,25 @@ async fn spc_screenshot(client: &Client, filepath: &str) -> util::Result<()> {
     Ok(())
 }
 
+/// Screenshot into `screenshot_dir`, if one is configured.
+async fn spc_snapshot(client: &Client, config: &DekaConfig, name: &str) -> util::Result<()> {
+    match &config.screenshot_dir {
+        Some(dir) => spc_screenshot(client, &dir.join(name).to_string_lossy()).await,
+        None => Ok(()),
+    }
+}
+
+#[tracing::instrument(skip(client, config))]
 async fn spc_deka_no(
     client: &Client,
+    config: &DekaConfig,
     deka_params: TGDekaNumber,
-    with_screenshot: bool,
 ) -> util::Result<Option<Vec<DekaInfo>>> {
-    spc_deka_init(client).await?;
+    let _timer = METRICS
+        .latency
+        .with_label_values(&["spc_deka_no"])
+        .start_timer();
+    spc_deka_init(client, config).await?;
     tracing::debug!("deka::spc_deka_no | Filling case no");
 
     let spc_form = client
@@ -443,7 +576,7 @@ async fn spc_deka_no(
         .await
         .context(error::FantocciniCmdSnafu)?;
 
-    spc_select_option(&client, "#search_doctype", "คำพิพากษาศาลฎีกา").await?;
+    spc_select_option(client, "#search_doctype", "คำพิพากษาศาลฎีกา").await?;
     spc_input(&spc_form, "#search_deka_no", &deka_params.deka_serial).await?;
     spc_input(
         &spc_form,
@@ -458,47 +591,45 @@ async fn spc_deka_no(
     )
     .await?;
 
-    if with_screenshot {
-        spc_screenshot(
-            &client,
-            &format!(
-                "./memo/tests/deka.supremecourt-no-form-{}-{}.png",
-                deka_params.deka_serial, deka_params.deka_year
-            ),
-        )
-        .await?;
-    }
+    spc_snapshot(
+        client,
+        config,
+        &format!(
+            "deka.supremecourt-no-form-{}-{}.png",
+            deka_params.deka_serial, deka_params.deka_year
+        ),
+    )
+    .await?;
 
-    spc_click(&client, "#submit_search_deka").await?;
+    spc_click(client, "#submit_search_deka").await?;
 
-    let res = spc_deka_exec(&client, deka_params.with_long_note).await;
+    let res = spc_deka_exec(client, config, deka_params.with_long_note).await;
 
-    if with_screenshot {
-        spc_screenshot(
-            &client,
-            format!(
-                "./memo/tests/deka.supremecourt-no-{}-{}.png",
-                deka_params.deka_serial, deka_params.deka_year
-            )
-            .as_str(),
-        )
-        .await?;
-    }
-
-    client
-        .close_window()
-        .await
-        .context(error::FantocciniCmdSnafu)?;
+    spc_snapshot(
+        client,
+        config,
+        &format!(
+            "deka.supremecourt-no-{}-{}.png",
+            deka_params.deka_serial, deka_params.deka_year
+        ),
+    )
+    .await?;
 
     res
 }
 
+#[tracing::instrument(skip(client, config, deka_params))]
 async fn spc_deka_search(
     client: &Client,
+    config: &DekaConfig,
     deka_params: TGDekaSearch,
-    with_screenshot: bool,
 ) -> util::Result<Option<Vec<DekaInfo>>> {
-    spc_deka_init(client).await?;
+    tracing::debug!("deka::spc_deka_search | Query {:?}", deka_params);
+    let _timer = METRICS
+        .latency
+        .with_label_values(&["spc_deka_search"])
+        .start_timer();
+    spc_deka_init(client, config).await?;
     let keyword_cmpl = deka_params.search_words.join(" .และ. ");
     tracing::debug!("deka::spc_deka_search | Filling Info");
 
@@ -507,10 +638,10 @@ async fn spc_deka_search(
         .await
         .context(error::FantocciniCmdSnafu)?;
     if let Some(law_name) = deka_params.search_law {
-        spc_click(&client, "#search-tab a[href=\"#advance-search\"]").await?;
+        spc_click(client, "#search-tab a[href=\"#advance-search\"]").await?;
         client
             .wait()
-            .at_most(Duration::from_secs(10))
+            .at_most(config.element_timeout())
             .for_element(Locator::Id("advance-search"))
             .await
             .context(error::FantocciniCmdSnafu)?;
@@ -518,14 +649,14 @@ async fn spc_deka_search(
             .form(Locator::Id("adv_search"))
             .await
             .context(error::FantocciniCmdSnafu)?;
-        spc_select_option(&client, "#adv_search_doctype", "คำพิพากษาศาลฎีกา").await?;
+        spc_select_option(client, "#adv_search_doctype", "คำพิพากษาศาลฎีกา").await?;
         spc_input(&spc_form, "#adv_search_word_stext_and_ltext", &keyword_cmpl).await?;
-        spc_click(&client, "#adv_search_temp_law_name").await?;
+        spc_click(client, "#adv_search_temp_law_name").await?;
         spc_input(&spc_form, "#adv_search_temp_law_name", &law_name).await?;
         // Wait for autocomplete dialog
         client
             .wait()
-            .at_most(Duration::from_secs(10))
+            .at_most(config.element_timeout())
             .for_element(Locator::Css("ul.ui-autocomplete"))
             .await
             .context(error::FantocciniCmdSnafu)?;
@@ -557,7 +688,7 @@ async fn spc_deka_search(
             spc_input(
                 &spc_form,
                 "#adv_search_deka_end_year",
-                &deka_params.case_to.unwrap_or_else(|| case_from).to_string(),
+                &deka_params.case_to.unwrap_or(case_from).to_string(),
             )
             .await?;
         }
@@ -566,13 +697,13 @@ async fn spc_deka_search(
             .execute(r#"window.scrollTo(0, document.body.scrollHeight);"#, vec![])
             .await
             .context(error::FantocciniCmdSnafu)?;
-        spc_click(&client, "#submit_adv_search_deka").await?;
+        spc_click(client, "#submit_adv_search_deka").await?;
     } else {
         let spc_form = client
             .form(Locator::Id("basic_search"))
             .await
             .context(error::FantocciniCmdSnafu)?;
-        spc_select_option(&client, "#search_doctype", "คำพิพากษาศาลฎีกา").await?;
+        spc_select_option(client, "#search_doctype", "คำพิพากษาศาลฎีกา").await?;
         spc_input(&spc_form, "#search_word", &keyword_cmpl).await?;
 
         if let Some(case_from) = deka_params.case_from {
@@ -580,159 +711,405 @@ async fn spc_deka_search(
             spc_input(
                 &spc_form,
                 "#search_deka_end_year",
-                &deka_params.case_to.unwrap_or_else(|| case_from).to_string(),
+                &deka_params.case_to.unwrap_or(case_from).to_string(),
             )
             .await?;
         }
 
-        spc_click(&client, "#submit_search_deka").await?;
+        spc_click(client, "#submit_search_deka").await?;
     }
 
-    let res = spc_deka_exec(&client, deka_params.with_long_note).await;
+    let res = spc_deka_exec(client, config, deka_params.with_long_note).await;
 
-    if with_screenshot {
-        spc_screenshot(
-            &client,
-            &format!("./memo/tests/deka.supremecourt-search-{}.png", keyword_cmpl),
-        )
-        .await?;
-    }
-    client
-        .close_window()
-        .await
-        .context(error::FantocciniCmdSnafu)?;
+    spc_snapshot(
+        client,
+        config,
+        &format!("deka.supremecourt-search-{}.png", keyword_cmpl),
+    )
+    .await?;
 
     res
 }
 
-async fn on_message(client: &Arc<Mutex<Client>>, pld: MessagePayload) -> TGResponse {
-    tracing::debug!("deka::deka_thread | Receive message {:?}", pld);
-
-    // Find DekaSuksa first
-    let res = match pld.info {
-        TGDeka::Number(deka) => {
-            match dekasuksa_deka_exec(format!("{}/{}", deka.deka_serial, deka.deka_year)).await {
-                Ok(dk) => Ok(dk),
-                Err(e) => {
-					tracing::debug!("deka::dekasuksa | Fetch error {:?}", e);
-                    let clnt = client.lock().await;
-                    spc_deka_no(&clnt, deka, false).await
-                }
-            }
+fn record_lookup<T>(source: &str, res: &util::Result<Option<T>>) {
+    METRICS.lookup(source, res);
+    if res.is_ok() {
+        HEALTH.succeeded(source);
+    }
+}
+
+/// Run a lookup on a pooled browser, retrying once on a fresh session
+/// if the one we got turns out to be dead.
+async fn spc_lookup(
+    pool: &BrowserPool,
+    config: &DekaConfig,
+    info: TGDeka,
+) -> util::Result<Option<Vec<DekaInfo>>> {
+    let mut retried = false;
+
+    loop {
+        let mut session = pool.acquire().await?;
+        let res = match info.clone() {
+            TGDeka::Number(deka) => spc_deka_no(&session, config, deka).await,
+            TGDeka::Search(deka) => spc_deka_search(&session, config, deka).await,
+        };
+
+        if session.check(&res) || retried {
+            return res;
         }
-        TGDeka::Search(deka) => {
-            match dekasuksa_deka_exec(format!(
-                "{} {} {} {}",
-                deka.search_words.join(" "),
-                deka.search_law.clone().unwrap_or_default(),
-                deka.search_law_no.clone().unwrap_or_default(),
-                match deka.case_from {
-                    Some(cf) => cf.to_string(),
-                    _ => "".to_string(),
-                }
-            ))
-            .await
-            {
-                Ok(dk) => Ok(dk),
-                _ => {
-                    let clnt = client.lock().await;
-                    spc_deka_search(&clnt, deka.clone(), false).await
+
+        retried = true;
+    }
+}
+
+fn dekasuksa_query(info: &TGDeka) -> String {
+    match info {
+        TGDeka::Number(deka) => format!("{}/{}", deka.deka_serial, deka.deka_year),
+        TGDeka::Search(deka) => format!(
+            "{} {} {} {}",
+            deka.search_words.join(" "),
+            deka.search_law.clone().unwrap_or_default(),
+            deka.search_law_no.clone().unwrap_or_default(),
+            match deka.case_from {
+                Some(cf) => cf.to_string(),
+                _ => "".to_string(),
+            }
+        ),
+    }
+}
+
+async fn on_message(
+    pool: Arc<BrowserPool>,
+    config: Arc<DekaConfig>,
+    pld: MessagePayload,
+) -> TGResponse {
+    tracing::debug!("deka::on_message | Receive message {:?}", pld);
+    let job_id = pld.job_id.clone();
+    METRICS.request(&pld.info);
+
+    // First source that answers wins, errors fall through to the next one.
+    // Streaming asks every source and answers with all they found.
+    let mut res = Ok(None);
+    let mut answered = false;
+    for source in config.sources.iter() {
+        job::running(source.as_str());
+        res = match source {
+            Source::Dekasuksa => dekasuksa_deka_exec(&config, dekasuksa_query(&pld.info)).await,
+            Source::Spc => spc_lookup(&pool, &config, pld.info.clone()).await,
+        };
+        record_lookup(source.as_str(), &res);
+
+        match &res {
+            Ok(_) if !pld.stream => break,
+            Ok(found) => {
+                answered = true;
+                if let Some(found) = found {
+                    job::partial(source.as_str(), found);
                 }
             }
+            Err(e) => tracing::debug!("deka::on_message | {} error {:?}", source.as_str(), e),
         }
-    };
+    }
+    if let Some(found) = job::found().filter(|_| answered) {
+        res = Ok(Some(found).filter(|found| !found.is_empty()));
+    }
 
     match res {
-        Ok(res) => TGResponse::Okay(TGResponseOkay {
-            from: "deka".to_string(),
-            message: pld.message,
-            result: res,
-        }),
+        Ok(res) => {
+            TGResponse::Okay(TGResponseOkay::new(pld.message, job_id, res).rendered(pld.parse_mode))
+        }
         Err(e) => TGResponse::Err(TGResponseErr {
-            from: "deka".to_string(),
-            message: pld.message,
-            error: format!("Unable to find Deka:\n{:?}", e),
+            details: Some(Report::from_error(&e).to_string()),
+            ..TGResponseErr::new(pld.message, job_id, e.code())
         }),
     }
 }
 
+/// Reply for a request the worker will not get to before it stops.
+pub fn restarting_response(mut pld: MessagePayload) -> TGResponse {
+    let job_id = pld.job_id().to_string();
+
+    TGResponse::Err(TGResponseErr {
+        error: i18n::restarting(pld.message.lang()).to_string(),
+        ..TGResponseErr::new(pld.message, Some(job_id), TGErrorCode::SourceUnavailable)
+    })
+}
+
+/// Last word on a cancelled request.
+pub fn cancelled_status(mut pld: MessagePayload, superseded_by: Option<String>) -> TGResponse {
+    let job_id = pld.job_id().to_string();
+
+    TGResponse::Status(TGJobStatus::new(
+        pld.message,
+        job_id,
+        TGJobState::Cancelled { superseded_by },
+    ))
+}
+
+/// Capabilities sent to the bot right after connecting.
+pub fn hello(config: &DekaConfig, worker_id: String) -> TGResponse {
+    TGResponse::Hello(TGHello {
+        protocol_version: PROTOCOL_VERSION,
+        worker_id,
+        worker_version: env!("CARGO_PKG_VERSION").to_string(),
+        modes: TGDeka::MODES.iter().map(|m| m.to_string()).collect(),
+        sources: config
+            .sources
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        max_concurrency: config.max_concurrency,
+    })
+}
+
+/// Reply for a request whose `info` could not be read.
+pub fn rejected_response(rejected: RejectedPayload) -> TGResponse {
+    TGResponse::Err(TGResponseErr {
+        reason: Some(rejected.reason),
+        details: Some(rejected.detail),
+        ..TGResponseErr::new(rejected.message, rejected.job_id, TGErrorCode::InvalidQuery)
+    })
+}
+
+/// Reply for a lookup reusing the `job_id` of one still queued or running.
+pub fn duplicate_response(pld: MessagePayload) -> TGResponse {
+    rejected_response(RejectedPayload {
+        detail: format!(
+            "job_id: {} is already in use",
+            pld.job_id.as_deref().unwrap_or_default()
+        ),
+        message: pld.message,
+        job_id: pld.job_id,
+        reason: TGErrorReason::InvalidRequest,
+    })
+}
+
+const HEALTH_CHECK: Duration = Duration::from_secs(60);
+
+fn begin_drain(ws_rx: &mut mpsc::Receiver<TGRequest>, grace: Duration) -> Instant {
+    tracing::info!(
+        "deka::deka_thread | Shutdown signal received, draining for {:?}.",
+        grace
+    );
+    ws_rx.close();
+
+    Instant::now() + grace
+}
+
 pub async fn deka_thread(
     mut sig_rx: broadcast::Receiver<()>,
-    mut ws_rx: mpsc::Receiver<MessagePayload>,
+    mut ws_rx: mpsc::Receiver<TGRequest>,
     tg_tx: mpsc::Sender<TGResponse>,
-) -> () {
-    let c = match ClientBuilder::native()
-        .connect("http://localhost:4444")
-        .await
-        .context(error::FantocciniSessionSnafu)
-    {
-        Ok(c) => match c.persist().await {
-            Ok(_) => c,
-            Err(e) => {
-                tracing::warn!("deka::deka_thread | Persist error: {:?}", e);
-                return ();
-            }
-        },
-        Err(e) => {
-            tracing::warn!("deka::deka_thread | Browser launch error: {:?}", e);
-            return ();
-        }
-    };
-
-    let client = Arc::new(Mutex::new(c));
+    mut config_rx: watch::Receiver<DekaConfig>,
+    browser: BrowserConfig,
+) {
+    let mut config = Arc::new(config_rx.borrow_and_update().clone());
+    let pool = Arc::new(BrowserPool::new(browser, config.spc_url.clone()));
+    let mut executor = Executor::new(config.max_concurrency);
+
+    // Lookups open sessions on demand, so keep serving without one
+    if let Err(e) = pool.warm_up().await {
+        tracing::warn!("deka::deka_thread | Browser launch error: {:?}", e);
+    }
 
-    loop {
-        tokio::select! {
-            Ok(_) = sig_rx.recv() => {
-                tracing::info!("deka::deka_thread | Shutdown signal received.");
-                break;
+    // Jobs cancelled on request, with the job that superseded them if any
+    let mut cancelled_jobs: HashMap<String, Option<String>> = HashMap::new();
+    let mut deadline: Option<Instant> = None;
+    let mut cancelled = false;
+    let mut rx_done = false;
+    let mut health_tick = interval_at(Instant::now() + HEALTH_CHECK, HEALTH_CHECK);
+
+    while !(rx_done && executor.is_empty()) {
+        let tg_msg = tokio::select! {
+            Ok(_) = sig_rx.recv(), if deadline.is_none() => {
+                deadline = Some(begin_drain(&mut ws_rx, config.grace()));
+                continue;
             },
-            Some(pld) = ws_rx.recv() => {
-                let tg_msg = on_message(&client, pld).await;
-                if let Err(e) = tg_tx.send(tg_msg.clone()).await {
-                    tracing::warn!(
-                        "deka::deka_thread | Unable to tx Telegram: {:?}\nMessage: {:?}",
-                        e,
-                        tg_msg
-                    );
+            Ok(_) = config_rx.changed() => {
+                // Jobs already running keep the config they started with
+                config = Arc::new(config_rx.borrow_and_update().clone());
+                tracing::info!("deka::deka_thread | Config reloaded.");
+                continue;
+            },
+            pld = ws_rx.recv(), if !rx_done => {
+                METRICS.queue_depth("ws_rx", ws_rx.len());
+                match pld {
+                    Some(TGRequest::Lookup(pld)) if deadline.is_some_and(|dl| Instant::now() >= dl) => {
+                        restarting_response(*pld)
+                    },
+                    Some(TGRequest::Lookup(pld)) if pld.job_id.as_deref().is_some_and(|job_id| executor.contains(job_id)) => {
+                        tracing::warn!("deka::deka_thread | Duplicate job_id {:?}", pld.job_id);
+                        duplicate_response(*pld)
+                    },
+                    Some(TGRequest::Lookup(pld)) => {
+                        let mut pld = *pld;
+                        let span = pld.span();
+                        let job = Job::new(tg_tx.clone(), &mut pld);
+                        job.status(TGJobState::Accepted {
+                            queue_position: executor.next_position(),
+                        });
+                        executor.spawn(
+                            pld.clone(),
+                            job.scope(on_message(pool.clone(), config.clone(), pld.clone()))
+                                .instrument(span),
+                        );
+                        if config.supersede {
+                            for job_id in executor.supersede(&pld) {
+                                tracing::info!("deka::deka_thread | {} superseded by {:?}", job_id, pld.job_id);
+                                cancelled_jobs.insert(job_id, pld.job_id.clone());
+                            }
+                        }
+                        continue;
+                    },
+                    Some(TGRequest::Cancel(cancel)) => {
+                        let job_ids = executor.cancel(&cancel);
+                        tracing::info!("deka::deka_thread | Cancel {:?}: {:?}", cancel, job_ids);
+                        cancelled_jobs.extend(job_ids.into_iter().map(|job_id| (job_id, None)));
+                        continue;
+                    },
+                    None => {
+                        rx_done = true;
+                        continue;
+                    },
                 }
             },
+            Some(res) = executor.next() => match res {
+                Ok(tg_msg) => {
+                    // Finished before the cancel got to it
+                    if let Some(job_id) = tg_msg.job_id() {
+                        cancelled_jobs.remove(job_id);
+                    }
+                    tg_msg
+                },
+                Err(pld) => match pld.job_id.as_ref().and_then(|job_id| cancelled_jobs.remove(job_id)) {
+                    Some(superseded_by) => cancelled_status(pld, superseded_by),
+                    // Jobs cut off by the drain deadline get a restart notice
+                    None => restarting_response(pld),
+                },
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() && !cancelled => {
+                tracing::info!(
+                    "deka::deka_thread | Grace period over, cancelling {} job(s).",
+                    executor.len()
+                );
+                cancelled = true;
+                executor.cancel_all();
+                continue;
+            },
+            _ = health_tick.tick() => {
+                let pool = pool.clone();
+                tokio::spawn(async move { pool.health_check().await });
+                continue;
+            },
+            else => break,
+        };
+
+        if let Err(e) = tg_tx.send(tg_msg.clone()).await {
+            tracing::warn!(
+                "deka::deka_thread | Unable to tx Telegram: {:?}\nMessage: {:?}",
+                e,
+                tg_msg
+            );
         }
+        METRICS.queue_depth("tg_rx", tg_tx.max_capacity() - tg_tx.capacity());
     }
 
-    let _ = Arc::try_unwrap(client).unwrap().into_inner().close().await;
+    tracing::info!("deka::deka_thread | Closing browser.");
+    pool.close().await;
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::model::{TGChat, TGMessageInfo, TGMessgae, TGUser};
-    use fantoccini::wd::Capabilities;
+    use crate::model::fixture;
 
     use super::*;
 
+    fn test_config() -> DekaConfig {
+        DekaConfig {
+            screenshot_dir: Some("./memo/tests".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hello_lists_capabilities() {
+        let config = DekaConfig {
+            sources: vec![Source::Spc],
+            ..Default::default()
+        };
+        let TGResponse::Hello(hello) = hello(&config, "w1".to_string()) else {
+            panic!("not a hello");
+        };
+
+        assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(hello.worker_id, "w1");
+        assert_eq!(hello.modes, vec!["number", "search"]);
+        assert_eq!(hello.sources, vec!["spc"]);
+        assert_eq!(hello.max_concurrency, config.max_concurrency);
+    }
+
+    #[test]
+    fn dekasuksa_post_fixture() {
+        let html = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/dekasuksa/post.html"
+        ))
+        .unwrap();
+        let deka = dekasuksa_post(&html).unwrap();
+
+        assert_eq!(deka.deka_no, "ฎีกาที่ 264/2567");
+        assert!(deka.short_note.contains("ฟ้องร้องบังคับคดีได้"));
+        assert_eq!(deka.metadata.law, "ป.พ.พ. มาตรา 653\nป.พ.พ. มาตรา 7");
+
+        // The blank line right after "เพิ่มเติม" does not start the law, and
+        // long paragraphs stay in the long note even after the law
+        let long_note = deka.long_note.unwrap();
+        let paras = long_note.split("\n\n").collect::<Vec<_>>();
+        assert_eq!(paras.len(), 3, "{:?}", paras);
+        assert!(paras[0].starts_with("จำเลยกู้ยืมเงินโจทก์"));
+        assert!(paras.iter().all(|p| p.chars().count() >= 100));
+        assert!(!long_note.contains("มาตรา 653"));
+    }
+
+    #[test]
+    fn dekasuksa_notes_without_law() {
+        let (short_note, long_note, meta_law) = dekasuksa_notes("สั้น\nเพิ่มเติม\n\nยาว");
+        assert_eq!(short_note, "สั้น\n");
+        assert_eq!(long_note.as_deref(), Some("ยาว"));
+        assert!(meta_law.is_none());
+
+        let (short_note, long_note, meta_law) = dekasuksa_notes("สั้น\n");
+        assert_eq!(short_note, "สั้น\n");
+        assert!(long_note.is_none() && meta_law.is_none());
+    }
+
+    #[test]
+    fn duplicate_job_id_is_rejected() {
+        let mut pld = fixture::payload(1);
+        pld.job_id = Some("job-1".to_string());
+        let TGResponse::Err(err) = duplicate_response(pld) else {
+            panic!("not an error");
+        };
+
+        assert_eq!(err.code, TGErrorCode::InvalidQuery);
+        assert_eq!(err.reason, Some(TGErrorReason::InvalidRequest));
+        assert_eq!(err.job_id.as_deref(), Some("job-1"));
+        assert!(!err.retryable);
+    }
+
     async fn get_browser() -> Client {
-        let cap: Capabilities =
-            serde_json::from_str(r#"{"moz:firefoxOptions":{"args":["--headless"]}}"#).unwrap();
-        ClientBuilder::native()
-            .capabilities(cap)
-            .connect("http://localhost:4444")
-            .await
-            .context(error::FantocciniSessionSnafu)
-            .unwrap()
+        BrowserConfig::default().connect().await.unwrap()
     }
 
     #[tokio::test]
     async fn spc_init_test() {
         let client = get_browser().await;
-        spc_deka_init(&client).await.unwrap();
+        spc_deka_init(&client, &test_config()).await.unwrap();
         spc_screenshot(&client, "./memo/tests/deka.supremecourt-test.png")
             .await
             .unwrap();
 
-        assert_eq!(
-            client.current_url().await.unwrap().to_string(),
-            "http://deka.supremecourt.or.th/"
-        );
+        assert_eq!(client.current_url().await.unwrap().to_string(), SPC_URL);
         client.close().await.unwrap();
     }
 
@@ -741,12 +1118,12 @@ mod tests {
         let client = get_browser().await;
         let deka_res = spc_deka_no(
             &client,
+            &test_config(),
             TGDekaNumber {
                 deka_serial: "264".to_string(),
                 deka_year: 2567,
                 with_long_note: false,
             },
-            true,
         )
         .await;
 
@@ -768,12 +1145,12 @@ mod tests {
         let client = get_browser().await;
         let deka_res = spc_deka_no(
             &client,
+            &test_config(),
             TGDekaNumber {
                 deka_serial: "264".to_string(),
                 deka_year: 2567,
                 with_long_note: true,
             },
-            true,
         )
         .await;
         println!("deka_res: {:?}", deka_res);
@@ -792,10 +1169,12 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::unnecessary_first_then_check)]
     async fn spc_deka_search_test() {
         let client = get_browser().await;
         let deka_res = spc_deka_search(
             &client,
+            &test_config(),
             TGDekaSearch {
                 search_law: Some("ประมวลกฎหมายแพ่งและพาณิชย์".into()),
                 search_words: ["เช่าซื้อ".to_string(), "รถยนต์".to_string()].to_vec(),
@@ -804,7 +1183,6 @@ mod tests {
                 case_to: Some(2567),
                 with_long_note: false,
             },
-            true,
         )
         .await;
         println!("deka_res: {:?}", deka_res);
@@ -823,7 +1201,7 @@ mod tests {
 
     #[tokio::test]
     async fn dks_test() {
-        let deka_res = dekasuksa_deka_exec("3853/2566".to_string()).await;
+        let deka_res = dekasuksa_deka_exec(&test_config(), "3853/2566".to_string()).await;
 
         println!("deka_res: {:?}", deka_res);
         assert!(deka_res.is_ok());
@@ -839,40 +1217,8 @@ mod tests {
 
     #[tokio::test]
     async fn deka_thread_test() {
-        let client = Arc::new(Mutex::new(get_browser().await));
-        let resp = on_message(
-            &client,
-            MessagePayload {
-                message: TGMessgae {
-                    update_id: 1234,
-                    message: TGMessageInfo {
-                        message_id: 1234,
-                        from: TGUser {
-                            id: 123456,
-                            is_bot: false,
-                            first_name: "test".to_string(),
-                            last_name: None,
-                            username: "tester".to_string(),
-                            language_code: "EN".to_string(),
-                        },
-                        chat: TGChat {
-                            id: 1234568,
-                            first_name: "test".to_string(),
-                            username: "tester".to_string(),
-                            chat_type: "general".to_string(),
-                        },
-                        date: 12334554,
-                        text: "ฎีกา 264/2567".to_string(),
-                    },
-                },
-                info: TGDeka::Number(TGDekaNumber {
-                    deka_serial: "264".to_string(),
-                    deka_year: 2567,
-                    with_long_note: false,
-                }),
-            },
-        )
-        .await;
+        let pool = Arc::new(BrowserPool::new(BrowserConfig::default(), SPC_URL));
+        let resp = on_message(pool, Arc::new(test_config()), fixture::payload(1234)).await;
         assert!(matches!(resp, TGResponse::Okay(_)));
     }
 }
@@ -0,0 +1,371 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use tokio::{
+    sync::{oneshot, watch, Semaphore},
+    task::{self, JoinSet},
+};
+
+use crate::model::{MessagePayload, TGCancel, TGErrorCode, TGResponse, TGResponseErr};
+
+/// What is kept of a job to find it again when it has to be cancelled.
+struct Handle {
+    update_id: i64,
+    sender: Option<(i64, i64)>,
+    seq: u64,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Runs each request as its own task, with at most `concurrency` of them
+/// doing work at the same time. The rest wait for a permit in arrival order.
+pub struct Executor {
+    jobs: JoinSet<Option<TGResponse>>,
+    /// The request of each task, kept out of it so a panicked task can
+    /// still be answered.
+    tasks: HashMap<task::Id, MessagePayload>,
+    handles: HashMap<String, Handle>,
+    seq: u64,
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+    cancel: watch::Sender<bool>,
+}
+
+impl Executor {
+    pub fn new(concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+
+        Self {
+            jobs: JoinSet::new(),
+            tasks: HashMap::new(),
+            handles: HashMap::new(),
+            seq: 0,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            cancel: watch::channel(false).0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Whether a job with this ID is queued or running.
+    pub fn contains(&self, job_id: &str) -> bool {
+        self.handles.contains_key(job_id)
+    }
+
+    /// Jobs still waiting for a permit.
+    pub fn queued(&self) -> usize {
+        let running = self.concurrency - self.permits.available_permits();
+
+        self.len().saturating_sub(running)
+    }
+
+    /// Where a job spawned now would wait: `0` when a permit is free,
+    /// otherwise its place in the queue.
+    pub fn next_position(&self) -> usize {
+        if self.len() < self.concurrency {
+            0
+        } else {
+            self.queued() + 1
+        }
+    }
+
+    /// Run `job` for `pld`. Its job ID must not be `contains`ed already.
+    pub fn spawn<F>(&mut self, mut pld: MessagePayload, job: F)
+    where
+        F: Future<Output = TGResponse> + Send + 'static,
+    {
+        let permits = self.permits.clone();
+        let mut cancel_all_rx = self.cancel.subscribe();
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let job_id = pld.job_id().to_string();
+
+        self.seq += 1;
+        self.handles.insert(
+            job_id,
+            Handle {
+                update_id: pld.message.update_id,
+                sender: pld.message.sender(),
+                seq: self.seq,
+                cancel: Some(cancel_tx),
+            },
+        );
+
+        let abort = self.jobs.spawn(async move {
+            let run = async {
+                let _permit = permits.acquire_owned().await;
+                job.await
+            };
+
+            tokio::select! {
+                tg_msg = run => Some(tg_msg),
+                Ok(_) = cancel_rx => None,
+                _ = cancel_all_rx.wait_for(|cancelled| *cancelled) => None,
+            }
+        });
+        self.tasks.insert(abort.id(), pld);
+    }
+
+    /// Next finished job. A cancelled job comes back as `Err` with its
+    /// request so the caller can still answer it, and a panicked one as an
+    /// `Internal` error.
+    pub async fn next(&mut self) -> Option<Result<TGResponse, MessagePayload>> {
+        let (id, res) = match self.jobs.join_next_with_id().await? {
+            Ok((id, tg_msg)) => (id, Ok(tg_msg)),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let mut pld = self.tasks.remove(&id)?;
+        let job_id = pld.job_id().to_string();
+
+        self.handles.remove(&job_id);
+
+        match res {
+            Ok(Some(tg_msg)) => Some(Ok(tg_msg)),
+            Ok(None) => Some(Err(pld)),
+            Err(e) => {
+                tracing::error!("executor::next | Job {} failed: {:?}", job_id, e);
+
+                Some(Ok(TGResponse::Err(TGResponseErr {
+                    details: Some(e.to_string()),
+                    ..TGResponseErr::new(pld.message, pld.job_id, TGErrorCode::Internal)
+                })))
+            }
+        }
+    }
+
+    fn cancel_where(&mut self, pred: impl Fn(&str, &Handle) -> bool) -> Vec<String> {
+        let mut cancelled = Vec::new();
+
+        for (job_id, handle) in self.handles.iter_mut() {
+            if !pred(job_id, handle) {
+                continue;
+            }
+            if let Some(cancel) = handle.cancel.take() {
+                let _ = cancel.send(());
+                cancelled.push(job_id.clone());
+            }
+        }
+
+        cancelled
+    }
+
+    /// Stop the jobs `target` names, queued or running. Returns their job IDs.
+    pub fn cancel(&mut self, target: &TGCancel) -> Vec<String> {
+        self.cancel_where(|job_id, handle| {
+            target.job_id.as_deref() == Some(job_id) || target.update_id == Some(handle.update_id)
+        })
+    }
+
+    /// Stop the jobs from the same sender as `pld` that came in before it.
+    pub fn supersede(&mut self, pld: &MessagePayload) -> Vec<String> {
+        let Some(sender) = pld.message.sender() else {
+            return Vec::new();
+        };
+        let seq = pld
+            .job_id
+            .as_ref()
+            .and_then(|job_id| self.handles.get(job_id))
+            .map(|handle| handle.seq)
+            .unwrap_or(u64::MAX);
+
+        self.cancel_where(|_, handle| handle.sender == Some(sender) && handle.seq < seq)
+    }
+
+    /// Stop every job at its next await point.
+    pub fn cancel_all(&mut self) {
+        self.cancel.send_replace(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use crate::model::{fixture, TGResponseOkay};
+
+    use super::*;
+
+    fn okay(pld: MessagePayload) -> TGResponse {
+        TGResponse::Okay(TGResponseOkay::new(pld.message, pld.job_id, None))
+    }
+
+    #[tokio::test]
+    async fn executor_respects_concurrency() {
+        let mut executor = Executor::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..6 {
+            let pld = fixture::payload(i);
+            let (running, peak) = (running.clone(), peak.clone());
+            executor.spawn(pld.clone(), async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                okay(pld)
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(executor.len(), 6);
+        assert_eq!(executor.queued(), 4);
+        assert_eq!(executor.next_position(), 5);
+
+        let mut done = 0;
+        while let Some(res) = executor.next().await {
+            assert!(res.is_ok());
+            done += 1;
+        }
+
+        assert_eq!(done, 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn executor_returns_cancelled_payloads() {
+        let mut executor = Executor::new(1);
+        let pld = fixture::payload(42);
+        executor.spawn(pld.clone(), async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            okay(pld)
+        });
+        executor.cancel_all();
+
+        match executor.next().await {
+            Some(Err(pld)) => assert_eq!(pld.message.update_id, 42),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(executor.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn executor_cancels_by_id_and_sender() {
+        let mut executor = Executor::new(1);
+        let mut plds = Vec::new();
+
+        for i in 0..3 {
+            let mut pld = fixture::payload(i);
+            pld.job_id = Some(format!("job-{}", i));
+            let res = pld.clone();
+            executor.spawn(pld.clone(), async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                okay(res)
+            });
+            plds.push(pld);
+        }
+
+        assert!(executor.contains("job-1") && !executor.contains("job-3"));
+
+        // Unknown or already cancelled jobs are skipped
+        assert!(executor.cancel(&TGCancel::default()).is_empty());
+        assert_eq!(
+            executor.cancel(&TGCancel {
+                job_id: None,
+                update_id: Some(1),
+            }),
+            vec!["job-1"]
+        );
+        assert!(executor
+            .cancel(&TGCancel {
+                job_id: Some("job-1".to_string()),
+                update_id: None,
+            })
+            .is_empty());
+
+        // The newest one from the same sender replaces the rest
+        assert_eq!(executor.supersede(&plds[2]), vec!["job-0"]);
+
+        let mut cancelled = Vec::new();
+        for _ in 0..2 {
+            match executor.next().await {
+                Some(Err(pld)) => cancelled.push(pld.message.update_id),
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+        cancelled.sort();
+        assert_eq!(cancelled, vec![0, 1]);
+        assert_eq!(executor.len(), 1);
+
+        executor.cancel_all();
+        assert!(matches!(executor.next().await, Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn executor_answers_panicked_jobs() {
+        let mut executor = Executor::new(1);
+        let mut pld = fixture::payload(7);
+        pld.job_id = Some("job-7".to_string());
+        executor.spawn(pld, async move { panic!("boom") });
+
+        match executor.next().await {
+            Some(Ok(TGResponse::Err(res))) => {
+                assert_eq!(res.code, TGErrorCode::Internal);
+                assert_eq!(res.job_id.as_deref(), Some("job-7"));
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(!executor.contains("job-7"));
+        assert!(executor.is_empty());
+        assert_eq!(executor.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn executor_frees_job_ids() {
+        let mut executor = Executor::new(1);
+        let mut pld = fixture::payload(3);
+        pld.job_id = Some("job-3".to_string());
+
+        // Finished
+        let res = pld.clone();
+        executor.spawn(pld.clone(), async move { okay(res) });
+        assert!(executor.contains("job-3"));
+        assert!(matches!(
+            executor.next().await,
+            Some(Ok(TGResponse::Okay(_)))
+        ));
+        assert!(!executor.contains("job-3"));
+
+        // Cancelled
+        let res = pld.clone();
+        executor.spawn(pld.clone(), async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            okay(res)
+        });
+        assert!(executor.contains("job-3"));
+        assert_eq!(
+            executor.cancel(&TGCancel {
+                job_id: Some("job-3".to_string()),
+                update_id: None,
+            }),
+            vec!["job-3"]
+        );
+        assert!(matches!(executor.next().await, Some(Err(_))));
+        assert!(!executor.contains("job-3"));
+
+        // Panicked
+        executor.spawn(pld.clone(), async move { panic!("boom") });
+        assert!(executor.contains("job-3"));
+        assert!(matches!(
+            executor.next().await,
+            Some(Ok(TGResponse::Err(_)))
+        ));
+        assert!(!executor.contains("job-3"));
+
+        // And once more after all of them
+        let res = pld.clone();
+        executor.spawn(pld, async move { okay(res) });
+        assert!(matches!(
+            executor.next().await,
+            Some(Ok(TGResponse::Okay(_)))
+        ));
+        assert!(executor.is_empty());
+    }
+}
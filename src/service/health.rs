@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+pub static HEALTH: Lazy<Health> = Lazy::new(Health::new);
+
+/// What `/healthz` and `/readyz` report on.
+pub struct Health {
+    bot_connected: AtomicBool,
+    browser_ready: AtomicBool,
+    progress: Mutex<Instant>,
+    last_success: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotStatus {
+    pub connected: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrowserStatus {
+    pub ready: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MainLoopStatus {
+    pub live: bool,
+    pub last_progress_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub live: bool,
+    pub ready: bool,
+    pub bot: BotStatus,
+    pub browser: BrowserStatus,
+    pub main_loop: MainLoopStatus,
+    /// Unix time of the last lookup each source answered
+    pub last_success: BTreeMap<String, u64>,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            bot_connected: AtomicBool::new(false),
+            browser_ready: AtomicBool::new(false),
+            progress: Mutex::new(Instant::now()),
+            last_success: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_bot_connected(&self, connected: bool) {
+        self.bot_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_browser_ready(&self, ready: bool) {
+        self.browser_ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Called by the main loop each time it comes around.
+    pub fn beat(&self) {
+        *self.progress.lock().unwrap() = Instant::now();
+    }
+
+    pub fn succeeded(&self, source: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.last_success
+            .lock()
+            .unwrap()
+            .insert(source.to_string(), now);
+    }
+
+    /// Bot linked and a browser session up.
+    pub fn is_ready(&self) -> bool {
+        self.bot_connected.load(Ordering::Relaxed) && self.browser_ready.load(Ordering::Relaxed)
+    }
+
+    /// `live` turns false once the main loop has been stuck for `stall`.
+    pub fn report(&self, stall: Duration) -> Report {
+        let since = self.progress.lock().unwrap().elapsed();
+        let live = since < stall;
+        let bot = BotStatus {
+            connected: self.bot_connected.load(Ordering::Relaxed),
+        };
+        let browser = BrowserStatus {
+            ready: self.browser_ready.load(Ordering::Relaxed),
+        };
+
+        Report {
+            live,
+            ready: live && bot.connected && browser.ready,
+            bot,
+            browser,
+            main_loop: MainLoopStatus {
+                live,
+                last_progress_secs: since.as_secs(),
+            },
+            last_success: self.last_success.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_needs_bot_and_browser() {
+        let health = Health::new();
+        assert!(health.report(Duration::from_secs(60)).live);
+        assert!(!health.report(Duration::from_secs(60)).ready);
+
+        health.set_bot_connected(true);
+        assert!(!health.report(Duration::from_secs(60)).ready);
+
+        health.set_browser_ready(true);
+        health.succeeded("spc");
+        let report = health.report(Duration::from_secs(60));
+        assert!(report.ready);
+        assert!(report.last_success.contains_key("spc"));
+    }
+
+    #[test]
+    fn stalled_main_loop_is_not_live() {
+        let health = Health::new();
+        health.set_bot_connected(true);
+        health.set_browser_ready(true);
+
+        let report = health.report(Duration::ZERO);
+        assert!(!report.live);
+        assert!(!report.ready);
+    }
+}
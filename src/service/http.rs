@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    error,
+    service::{health::HEALTH, metrics::METRICS},
+    util,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8 * 1024;
+/// Wait after a failed accept, so running out of descriptors does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Local HTTP endpoint for monitoring.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub listen: String,
+    /// `/healthz` fails once the main loop has been stuck this long
+    pub liveness_timeout_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "127.0.0.1:9464".to_string(),
+            liveness_timeout_secs: 60,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn liveness_timeout(&self) -> Duration {
+        Duration::from_secs(self.liveness_timeout_secs)
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn health(ok: bool, report: &impl Serialize) -> Self {
+        Self {
+            status: if ok {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            },
+            content_type: "application/json",
+            body: serde_json::to_string(report).unwrap_or_default(),
+        }
+    }
+
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+}
+
+fn route(method: &str, path: &str, stall: Duration) -> Response {
+    if method != "GET" {
+        return Response::text("405 Method Not Allowed", "method not allowed\n");
+    }
+
+    match path.split('?').next().unwrap_or_default() {
+        "/metrics" => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: METRICS.render(),
+        },
+        "/healthz" => {
+            let report = HEALTH.report(stall);
+            Response::health(report.live, &report)
+        }
+        "/readyz" => {
+            let report = HEALTH.report(stall);
+            Response::health(report.ready, &report)
+        }
+        _ => Response::text("404 Not Found", "not found\n"),
+    }
+}
+
+async fn read_request(strm: &mut TcpStream, stall: Duration) -> util::Result<Option<Response>> {
+    let mut buf = vec![0u8; MAX_REQUEST];
+    let mut len = 0;
+
+    loop {
+        let n = strm.read(&mut buf[len..]).await.context(error::IOSnafu)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        len += n;
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf[..len]) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Ok(Some(route(
+                    req.method.unwrap_or_default(),
+                    req.path.unwrap_or_default(),
+                    stall,
+                )));
+            }
+            Ok(httparse::Status::Partial) if len < buf.len() => continue,
+            Ok(httparse::Status::Partial) => {
+                return Ok(Some(Response::text(
+                    "431 Request Header Fields Too Large",
+                    "request too large\n",
+                )));
+            }
+            Err(_) => return Ok(Some(Response::text("400 Bad Request", "bad request\n"))),
+        }
+    }
+}
+
+async fn handle(mut strm: TcpStream, stall: Duration) -> util::Result<()> {
+    let Ok(res) = tokio::time::timeout(READ_TIMEOUT, read_request(&mut strm, stall)).await else {
+        return Ok(());
+    };
+    let Some(res) = res? else {
+        return Ok(());
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        res.status,
+        res.content_type,
+        res.body.len()
+    );
+    strm.write_all(head.as_bytes())
+        .await
+        .context(error::IOSnafu)?;
+    strm.write_all(res.body.as_bytes())
+        .await
+        .context(error::IOSnafu)?;
+    strm.shutdown().await.context(error::IOSnafu)?;
+
+    Ok(())
+}
+
+pub async fn bind(config: &HttpConfig) -> util::Result<TcpListener> {
+    TcpListener::bind(&config.listen)
+        .await
+        .context(error::IOSnafu)
+}
+
+/// Answer monitoring requests until the task is aborted.
+pub async fn serve(listener: TcpListener, config: HttpConfig) -> util::Result<()> {
+    let stall = config.liveness_timeout();
+
+    loop {
+        let strm = match listener.accept().await {
+            Ok((strm, _)) => strm,
+            Err(e) => {
+                // Usually running out of file descriptors, keep serving
+                tracing::warn!("http::serve | Accept error: {:?}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(strm, stall).await {
+                tracing::debug!("http::serve | Request error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr, req: &str) -> String {
+        let mut strm = TcpStream::connect(addr).await.unwrap();
+        strm.write_all(req.as_bytes()).await.unwrap();
+
+        let mut res = String::new();
+        strm.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_and_health() {
+        let config = HttpConfig {
+            listen: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        };
+        let listener = bind(&config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, config));
+        METRICS.ws_reconnects.inc();
+
+        let res = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.contains("deka_ws_reconnects_total"));
+
+        let res = get(addr, "GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 404"));
+
+        HEALTH.beat();
+        let res = get(addr, "GET /healthz HTTP/1.1\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains(r#""bot":{"connected":"#));
+
+        let res = get(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 503"));
+
+        let res = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 405"));
+
+        server.abort();
+    }
+}
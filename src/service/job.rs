@@ -0,0 +1,289 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tokio::sync::mpsc;
+
+use crate::{
+    i18n::{Labels, Lang},
+    model::{
+        DekaInfo, MessagePayload, TGJobState, TGJobStatus, TGJobStep, TGMessgae, TGResponse,
+        TGResponsePartial,
+    },
+    render::{self, ParseMode},
+};
+
+tokio::task_local! {
+    static JOB: Job;
+}
+
+#[derive(Debug, Default)]
+struct Found {
+    seq: u32,
+    result: Vec<DekaInfo>,
+}
+
+/// What two sources writing the same deka number agree on: Arabic digits
+/// and `NUMBER/YEAR` without spaces or the "ฎีกาที่" in front.
+fn deka_key(deka_no: &str) -> String {
+    static NUMBER: Lazy<Regex> = Lazy::new(|| Regex::new(r"([0-9]+)\s*/\s*([0-9]+)").unwrap());
+
+    let arabic = deka_no
+        .chars()
+        .map(|c| match c {
+            '๐'..='๙' => char::from_digit(c as u32 - '๐' as u32, 10).unwrap_or(c),
+            c => c,
+        })
+        .collect::<String>();
+
+    match NUMBER.captures_iter(&arabic).last() {
+        Some(cpt) => format!("{}/{}", &cpt[1], &cpt[2]),
+        None => arabic.split_whitespace().collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Add `new` to `result`. A deka already there only gets the long note
+/// it was missing.
+fn merge(result: &mut Vec<DekaInfo>, new: &[DekaInfo]) -> bool {
+    let mut changed = false;
+
+    for deka in new {
+        let key = deka_key(&deka.deka_no);
+        match result.iter_mut().find(|dr| deka_key(&dr.deka_no) == key) {
+            Some(dr) if dr.long_note.is_none() && deka.long_note.is_some() => {
+                dr.long_note = deka.long_note.clone();
+                changed = true;
+            }
+            Some(_) => {}
+            None => {
+                result.push(deka.clone());
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+/// Tells the bot how a request is getting on. Statuses and partial
+/// results are best effort: when the channel to the bot is full they are
+/// dropped rather than holding up the lookup.
+#[derive(Clone, Debug)]
+pub struct Job {
+    tg_tx: mpsc::Sender<TGResponse>,
+    message: TGMessgae,
+    job_id: String,
+    lang: Lang,
+    stream: bool,
+    parse_mode: Option<ParseMode>,
+    found: Arc<Mutex<Found>>,
+}
+
+impl Job {
+    pub fn new(tg_tx: mpsc::Sender<TGResponse>, pld: &mut MessagePayload) -> Self {
+        Self {
+            tg_tx,
+            job_id: pld.job_id().to_string(),
+            message: pld.message.clone(),
+            lang: pld.message.lang(),
+            stream: pld.stream,
+            parse_mode: pld.parse_mode,
+            found: Arc::default(),
+        }
+    }
+
+    fn send(&self, tg_msg: TGResponse) {
+        if let Err(e) = self.tg_tx.try_send(tg_msg) {
+            tracing::debug!("job::send | Dropped: {:?}", e);
+        }
+    }
+
+    pub fn status(&self, state: TGJobState) {
+        self.send(TGResponse::Status(TGJobStatus::new(
+            self.message.clone(),
+            self.job_id.clone(),
+            state,
+        )));
+    }
+
+    /// Merge what `source` found and, when streaming, send the lot if it grew.
+    pub fn partial(&self, source: &str, result: &[DekaInfo]) {
+        let mut found = self.found.lock().unwrap();
+        if !merge(&mut found.result, result) || !self.stream {
+            return;
+        }
+        found.seq += 1;
+
+        let labels = Labels::new(self.lang);
+        let parts = self
+            .parse_mode
+            .map(|mode| render::render(mode, &labels, &found.result))
+            .unwrap_or_default();
+
+        self.send(TGResponse::Partial(TGResponsePartial {
+            from: "deka".to_string(),
+            message: self.message.clone(),
+            job_id: self.job_id.clone(),
+            seq: found.seq,
+            source: source.to_string(),
+            result: found.result.clone(),
+            labels: Some(labels),
+            parse_mode: self.parse_mode,
+            parts,
+        }));
+    }
+
+    /// Everything `partial` was given so far.
+    pub fn found(&self) -> Vec<DekaInfo> {
+        self.found.lock().unwrap().result.clone()
+    }
+
+    /// Run `fut` with this job as the one `running` and `progress` report on.
+    pub fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        JOB.scope(self, fut)
+    }
+}
+
+fn current(state: TGJobState) {
+    let _ = JOB.try_with(|job| job.status(state));
+}
+
+/// The current job moved on to `source`. Does nothing outside a job.
+pub fn running(source: &str) {
+    current(TGJobState::Running {
+        source: source.to_string(),
+    });
+}
+
+/// The current job reached `step`. Does nothing outside a job.
+pub fn progress(step: TGJobStep) {
+    current(TGJobState::Progress { step });
+}
+
+/// `source` found `result` for the current job. Does nothing outside a job.
+pub fn partial(source: &str, result: &[DekaInfo]) {
+    let _ = JOB.try_with(|job| job.partial(source, result));
+}
+
+/// Language of the current job, the default outside a job.
+pub fn lang() -> Lang {
+    JOB.try_with(|job| job.lang).unwrap_or_default()
+}
+
+/// What the current job found so far, `None` outside a job.
+pub fn found() -> Option<Vec<DekaInfo>> {
+    JOB.try_with(|job| job.found()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{fixture, DekaMetadata};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_inside_scope_only() {
+        let (tg_tx, mut tg_rx) = mpsc::channel(2);
+        let mut pld = fixture::payload(5);
+        let job = Job::new(tg_tx, &mut pld);
+
+        progress(TGJobStep::Searching);
+        job.scope(async {
+            running("spc");
+            progress(TGJobStep::Searching);
+            progress(TGJobStep::FetchingLongNotes);
+        })
+        .await;
+
+        let states = std::iter::from_fn(|| tg_rx.try_recv().ok())
+            .map(|tg_msg| match tg_msg {
+                TGResponse::Status(status) => {
+                    assert_eq!(Some(status.job_id), pld.job_id.clone());
+                    status.state
+                }
+                tg_msg => panic!("unexpected {:?}", tg_msg),
+            })
+            .collect::<Vec<_>>();
+
+        // The channel holds two, the third status is dropped
+        assert_eq!(
+            states,
+            vec![
+                TGJobState::Running {
+                    source: "spc".to_string()
+                },
+                TGJobState::Progress {
+                    step: TGJobStep::Searching
+                },
+            ]
+        );
+    }
+
+    fn deka(deka_no: &str, long_note: Option<&str>) -> DekaInfo {
+        DekaInfo {
+            deka_no: deka_no.to_string(),
+            short_note: "short".to_string(),
+            long_note: long_note.map(|ln| ln.to_string()),
+            metadata: DekaMetadata {
+                law: "law".to_string(),
+                source: "source".to_string(),
+                url: None,
+            },
+        }
+    }
+
+    #[test]
+    fn merges_the_same_number_written_differently() {
+        let mut result = vec![deka("ฎีกาที่ 264/2567", None)];
+
+        assert!(!merge(&mut result, &[deka("๒๖๔ / ๒๕๖๗", None)]));
+        assert!(merge(&mut result, &[deka("264 /2567", Some("long"))]));
+        assert!(merge(&mut result, &[deka("265/2567", None)]));
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].deka_no, "ฎีกาที่ 264/2567");
+        assert_eq!(result[0].long_note.as_deref(), Some("long"));
+    }
+
+    #[tokio::test]
+    async fn streams_merged_results() {
+        let (tg_tx, mut tg_rx) = mpsc::channel(8);
+        let mut pld = fixture::payload(9);
+        pld.stream = true;
+        let job = Job::new(tg_tx, &mut pld);
+
+        let found = job
+            .scope(async {
+                partial("dekasuksa", &[deka("1/2567", None)]);
+                partial("spc", &[deka("1/2567", None), deka("2/2567", None)]);
+                // Nothing new, nothing sent
+                partial("spc", &[deka("2/2567", None)]);
+                partial("spc", &[deka("1/2567", Some("long"))]);
+                found()
+            })
+            .await
+            .unwrap();
+
+        let partials = std::iter::from_fn(|| tg_rx.try_recv().ok())
+            .map(|tg_msg| match tg_msg {
+                TGResponse::Partial(partial) => (partial.seq, partial.source, partial.result.len()),
+                tg_msg => panic!("unexpected {:?}", tg_msg),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            partials,
+            vec![
+                (1, "dekasuksa".to_string(), 1),
+                (2, "spc".to_string(), 2),
+                (3, "spc".to_string(), 2),
+            ]
+        );
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].long_note.as_deref(), Some("long"));
+        assert!(super::found().is_none());
+    }
+}
@@ -0,0 +1,159 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Registry, TextEncoder,
+};
+
+use crate::model::TGDeka;
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Everything exported on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Lookups received, by `mode`
+    pub requests: IntCounterVec,
+    /// Lookup outcomes by `source` (dekasuksa, spc) and `outcome` (found, empty, failed)
+    pub lookups: IntCounterVec,
+    /// Scraper run time by `scraper` function
+    pub latency: HistogramVec,
+    /// Messages waiting in a channel, by `channel`
+    pub queue_depth: IntGaugeVec,
+    /// Requests refused by `reason` (unsupported_mode, invalid_request, unparseable)
+    pub rejected: IntCounterVec,
+    pub session_restarts: IntCounter,
+    pub ws_reconnects: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("deka".to_string()), None).unwrap();
+
+        let requests = IntCounterVec::new(
+            opts!("requests_total", "Lookups received by mode"),
+            &["mode"],
+        )
+        .unwrap();
+        let lookups = IntCounterVec::new(
+            opts!("lookups_total", "Lookup outcomes by source"),
+            &["source", "outcome"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            histogram_opts!(
+                "scraper_duration_seconds",
+                "Scraper run time",
+                exponential_buckets(0.25, 2.0, 9).unwrap()
+            ),
+            &["scraper"],
+        )
+        .unwrap();
+        let queue_depth = IntGaugeVec::new(
+            opts!("queue_depth", "Messages waiting in a channel"),
+            &["channel"],
+        )
+        .unwrap();
+        let rejected = IntCounterVec::new(
+            opts!(
+                "rejected_requests_total",
+                "Requests that could not be parsed"
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let session_restarts = IntCounter::new(
+            "webdriver_session_restarts_total",
+            "WebDriver sessions thrown away because they died",
+        )
+        .unwrap();
+        let ws_reconnects =
+            IntCounter::new("ws_reconnects_total", "WebSocket reconnects to the bot").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(lookups.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(rejected.clone())).unwrap();
+        registry
+            .register(Box::new(session_restarts.clone()))
+            .unwrap();
+        registry.register(Box::new(ws_reconnects.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            lookups,
+            latency,
+            queue_depth,
+            rejected,
+            session_restarts,
+            ws_reconnects,
+        }
+    }
+
+    pub fn request(&self, info: &TGDeka) {
+        let mode = match info {
+            TGDeka::Number(_) => "number",
+            TGDeka::Search(_) => "search",
+        };
+
+        self.requests.with_label_values(&[mode]).inc();
+    }
+
+    pub fn lookup<T, E>(&self, source: &str, res: &Result<Option<T>, E>) {
+        let outcome = match res {
+            Ok(Some(_)) => "found",
+            Ok(None) => "empty",
+            Err(_) => "failed",
+        };
+
+        self.lookups.with_label_values(&[source, outcome]).inc();
+    }
+
+    pub fn rejected(&self, reason: &str) {
+        self.rejected.with_label_values(&[reason]).inc();
+    }
+
+    pub fn queue_depth(&self, channel: &str, depth: usize) {
+        self.queue_depth
+            .with_label_values(&[channel])
+            .set(depth as i64);
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buf);
+
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::fixture;
+
+    use super::*;
+
+    #[test]
+    fn render_includes_labels() {
+        let metrics = Metrics::new();
+        metrics.request(&fixture::payload(1).info);
+        metrics.lookup::<(), ()>("spc", &Err(()));
+        metrics.lookup("dekasuksa", &Ok::<_, ()>(Some(())));
+        metrics.queue_depth("ws_rx", 3);
+        metrics.rejected("unsupported_mode");
+        metrics
+            .latency
+            .with_label_values(&["spc_deka_no"])
+            .observe(1.5);
+
+        let txt = metrics.render();
+        assert!(txt.contains(r#"deka_requests_total{mode="number"} 1"#));
+        assert!(txt.contains(r#"deka_lookups_total{outcome="failed",source="spc"} 1"#));
+        assert!(txt.contains(r#"deka_lookups_total{outcome="found",source="dekasuksa"} 1"#));
+        assert!(txt.contains(r#"deka_queue_depth{channel="ws_rx"} 3"#));
+        assert!(txt.contains(r#"deka_rejected_requests_total{reason="unsupported_mode"} 1"#));
+        assert!(txt.contains(r#"deka_scraper_duration_seconds_count{scraper="spc_deka_no"} 1"#));
+    }
+}
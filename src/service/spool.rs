@@ -0,0 +1,244 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::{error, model::TGResponse, util};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+struct SpoolEntry {
+    expires_at: u64,
+    response: TGResponse,
+}
+
+/// Bounded queue of responses that could not be sent to the bot.
+/// When `path` is set, the queue is mirrored to a JSONL file so it
+/// survives a restart as well as a reconnect.
+#[derive(Debug)]
+pub struct Spool {
+    entries: VecDeque<SpoolEntry>,
+    capacity: usize,
+    ttl: Duration,
+    path: Option<PathBuf>,
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        Self::new(500, Duration::from_secs(15 * 60))
+    }
+}
+
+pub(crate) fn unix_now() -> util::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context(error::SystemTimeSnafu)?
+        .as_secs())
+}
+
+impl Spool {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+            ttl,
+            path: None,
+        }
+    }
+
+    /// Back the spool with a JSONL file, picking up whatever a previous run left.
+    pub async fn with_path(mut self, path: impl Into<PathBuf>) -> util::Result<Self> {
+        let path = path.into();
+
+        match tokio::fs::read_to_string(&path).await {
+            Ok(txt) => {
+                for line in txt.lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<SpoolEntry>(line) {
+                        Ok(entry) => self.entries.push_back(entry),
+                        Err(e) => tracing::warn!("spool::with_path | Skip bad line: {:?}", e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::IOSnafu),
+        }
+
+        self.path = Some(path);
+        self.prune()?;
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+        self.persist().await?;
+
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queue a response, evicting the oldest one when the spool is full.
+    pub async fn push(&mut self, response: TGResponse) -> util::Result<()> {
+        if self.capacity == 0 {
+            tracing::warn!("spool::push | Spool disabled, dropping {:?}", response);
+            return Ok(());
+        }
+
+        while self.entries.len() >= self.capacity {
+            if let Some(old) = self.entries.pop_front() {
+                tracing::warn!("spool::push | Spool full, dropping {:?}", old.response);
+            }
+        }
+
+        self.entries.push_back(SpoolEntry {
+            expires_at: unix_now()? + self.ttl.as_secs(),
+            response,
+        });
+        self.persist().await
+    }
+
+    /// Oldest response that has not expired yet.
+    pub fn front(&mut self) -> util::Result<Option<&TGResponse>> {
+        self.prune()?;
+
+        Ok(self.entries.front().map(|e| &e.response))
+    }
+
+    /// Drop the response returned by `front` once it has been delivered.
+    pub async fn pop_front(&mut self) -> util::Result<Option<TGResponse>> {
+        let res = self.entries.pop_front().map(|e| e.response);
+        self.persist().await?;
+
+        Ok(res)
+    }
+
+    fn prune(&mut self) -> util::Result<()> {
+        let now = unix_now()?;
+
+        while let Some(entry) = self.entries.front() {
+            if entry.expires_at > now {
+                break;
+            }
+
+            tracing::warn!("spool::prune | Expired {:?}", entry.response);
+            self.entries.pop_front();
+        }
+
+        Ok(())
+    }
+
+    async fn persist(&self) -> util::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut buf = String::new();
+        for entry in self.entries.iter() {
+            buf.push_str(&serde_json::to_string(entry).context(error::SerdeJsonSnafu)?);
+            buf.push('\n');
+        }
+
+        // Replace the file whole so a crash never leaves it half written
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut fs = tokio::fs::File::create(&tmp)
+            .await
+            .context(error::IOSnafu)?;
+        fs.write_all(buf.as_bytes()).await.context(error::IOSnafu)?;
+        fs.sync_all().await.context(error::IOSnafu)?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .context(error::IOSnafu)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{fixture, TGErrorCode, TGResponseErr};
+
+    use super::*;
+
+    fn response(update_id: i64) -> TGResponse {
+        TGResponse::Err(TGResponseErr::new(
+            fixture::message(update_id),
+            None,
+            TGErrorCode::Internal,
+        ))
+    }
+
+    fn update_id(resp: &TGResponse) -> i64 {
+        match resp {
+            TGResponse::Okay(r) => r.message.update_id,
+            TGResponse::Err(r) => r.message.update_id,
+            TGResponse::Hello(_) | TGResponse::Status(_) | TGResponse::Partial(_) => {
+                unreachable!()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spool_keeps_order_and_capacity() {
+        let mut spool = Spool::new(2, Duration::from_secs(60));
+        spool.push(response(1)).await.unwrap();
+        spool.push(response(2)).await.unwrap();
+        spool.push(response(3)).await.unwrap();
+
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().unwrap().map(update_id), Some(2));
+        assert_eq!(
+            spool.pop_front().await.unwrap().as_ref().map(update_id),
+            Some(2)
+        );
+        assert_eq!(
+            spool.pop_front().await.unwrap().as_ref().map(update_id),
+            Some(3)
+        );
+        assert!(spool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn spool_drops_expired() {
+        let mut spool = Spool::new(10, Duration::ZERO);
+        spool.push(response(1)).await.unwrap();
+
+        assert!(spool.front().unwrap().is_none());
+        assert!(spool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn spool_survives_restart() {
+        let path = std::env::temp_dir().join(format!("spool-test-{}.jsonl", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let mut spool = Spool::new(10, Duration::from_secs(60))
+            .with_path(&path)
+            .await
+            .unwrap();
+        spool.push(response(1)).await.unwrap();
+        spool.push(response(2)).await.unwrap();
+        spool.pop_front().await.unwrap();
+        drop(spool);
+
+        let mut spool = Spool::new(10, Duration::from_secs(60))
+            .with_path(&path)
+            .await
+            .unwrap();
+        assert_eq!(spool.len(), 1);
+        assert_eq!(spool.front().unwrap().map(update_id), Some(2));
+        assert!(!path.with_extension("jsonl.tmp").exists());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    env,
+    os::{linux::net::SocketAddrExt, unix::net},
+    time::Duration,
+};
+
+/// Talks to systemd over `NOTIFY_SOCKET` (`Type=notify`). Does nothing
+/// when the worker was not started by systemd.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    socket: Option<(net::UnixDatagram, net::SocketAddr)>,
+    watchdog: Option<Duration>,
+    ready: bool,
+    stopping: bool,
+    status: String,
+}
+
+fn socket_addr(path: &str) -> std::io::Result<net::SocketAddr> {
+    match path.strip_prefix('@') {
+        Some(name) => net::SocketAddr::from_abstract_name(name),
+        None => net::SocketAddr::from_pathname(path),
+    }
+}
+
+impl Notifier {
+    pub fn new(notify_socket: Option<&str>, watchdog: Option<Duration>) -> Self {
+        let socket = notify_socket.and_then(|path| {
+            let addr = socket_addr(path).ok()?;
+            let socket = net::UnixDatagram::unbound().ok()?;
+            socket.set_nonblocking(true).ok()?;
+
+            Some((socket, addr))
+        });
+
+        Self {
+            socket,
+            watchdog: watchdog.filter(|_| notify_socket.is_some()),
+            ..Default::default()
+        }
+    }
+
+    /// Read `NOTIFY_SOCKET`, `WATCHDOG_USEC` and `WATCHDOG_PID` as set by systemd.
+    pub fn from_env() -> Self {
+        let notify_socket = env::var("NOTIFY_SOCKET").ok();
+        let for_us = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| for_us && *usec > 0)
+            .map(Duration::from_micros);
+
+        Self::new(notify_socket.as_deref(), watchdog)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// How often to send `WATCHDOG=1`: half of what systemd allows.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|wd| wd / 2)
+    }
+
+    fn notify(&self, state: &str) {
+        let Some((socket, addr)) = &self.socket else {
+            return;
+        };
+
+        if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+            tracing::warn!("systemd::notify | Unable to send {:?}: {:?}", state, e);
+        }
+    }
+
+    /// `READY=1`, sent once.
+    pub fn ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.notify("READY=1");
+        }
+    }
+
+    /// `STATUS=...`, sent only when it changed.
+    pub fn status(&mut self, status: String) {
+        if self.status != status {
+            self.notify(&format!("STATUS={}", status));
+            self.status = status;
+        }
+    }
+
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    /// `STOPPING=1`, sent once.
+    pub fn stopping(&mut self) {
+        if !self.stopping {
+            self.stopping = true;
+            self.notify("STOPPING=1");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv(socket: &net::UnixDatagram) -> String {
+        let mut buf = [0u8; 256];
+        let n = socket.recv(&mut buf).unwrap();
+
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[test]
+    fn sends_states_to_socket() {
+        let path = env::temp_dir().join(format!("notify-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = net::UnixDatagram::bind(&path).unwrap();
+
+        let mut notifier = Notifier::new(path.to_str(), Some(Duration::from_secs(30)));
+        assert!(notifier.enabled());
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(15)));
+
+        notifier.ready();
+        notifier.ready();
+        notifier.status("1 queued".to_string());
+        notifier.status("1 queued".to_string());
+        notifier.watchdog();
+        notifier.stopping();
+        notifier.stopping();
+
+        assert_eq!(recv(&server), "READY=1");
+        assert_eq!(recv(&server), "STATUS=1 queued");
+        assert_eq!(recv(&server), "WATCHDOG=1");
+        assert_eq!(recv(&server), "STOPPING=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn without_socket_is_noop() {
+        let mut notifier = Notifier::new(None, Some(Duration::from_secs(30)));
+
+        assert!(!notifier.enabled());
+        assert_eq!(notifier.watchdog_interval(), None);
+        notifier.ready();
+        notifier.stopping();
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+};
+
+use crate::{error, service::bot::Backoff, util};
+
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Per `/status` request, so a driver that accepts but never answers is
+/// polled again.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A WebDriver binary (geckodriver, chromedriver) run as our own child process.
+/// It keeps the same port across restarts so pooled sessions can reconnect.
+pub struct Driver {
+    binary: PathBuf,
+    port: u16,
+    child: Child,
+}
+
+pub fn pick_free_port() -> util::Result<u16> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).context(error::IOSnafu)?;
+
+    Ok(listener.local_addr().context(error::IOSnafu)?.port())
+}
+
+fn forward_log<R>(name: &'static str, reader: Option<R>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let Some(reader) = reader else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            tracing::info!("webdriver::{} | {}", name, line);
+        }
+    });
+}
+
+/// Poll `/status` until the driver reports ready.
+pub async fn wait_ready(url: &str, timeout: Duration) -> util::Result<()> {
+    let started = Instant::now();
+    let status_url = format!("{}/status", url);
+    let client = reqwest::Client::new();
+
+    while let Some(left) = timeout.checked_sub(started.elapsed()) {
+        let probe = client.get(&status_url).timeout(left.min(PROBE_TIMEOUT));
+        if let Ok(res) = probe.send().await {
+            if let Ok(status) = res.json::<serde_json::Value>().await {
+                if status["value"]["ready"].as_bool().unwrap_or(false) {
+                    return Ok(());
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    error::WebDriverReadySnafu.fail()
+}
+
+impl Driver {
+    /// Launch the driver on a free port and wait until it accepts sessions.
+    pub async fn start(binary: impl Into<PathBuf>) -> util::Result<Self> {
+        let binary = binary.into();
+        let port = pick_free_port()?;
+        let child = Self::spawn(&binary, port).await?;
+
+        Ok(Self {
+            binary,
+            port,
+            child,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    async fn spawn(binary: &Path, port: u16) -> util::Result<Child> {
+        tracing::info!("webdriver::spawn | Starting {:?} on port {}", binary, port);
+        let mut child = Command::new(binary)
+            .arg("--port")
+            .arg(port.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(error::IOSnafu)?;
+
+        forward_log("stdout", child.stdout.take());
+        forward_log("stderr", child.stderr.take());
+
+        if let Err(e) = wait_ready(&format!("http://127.0.0.1:{}", port), READY_TIMEOUT).await {
+            let _ = child.kill().await;
+            return Err(e);
+        }
+
+        Ok(child)
+    }
+
+    /// Restart the driver whenever it exits. Runs until the task is aborted,
+    /// which kills the child with it, or until restarting keeps failing.
+    pub async fn supervise(mut self) -> util::Result<()> {
+        let mut backoff = Backoff::default();
+
+        loop {
+            let status = self.child.wait().await.context(error::IOSnafu)?;
+            tracing::warn!("webdriver::supervise | Driver exited: {}", status);
+
+            self.child = loop {
+                let Some(delay) = backoff.next_delay() else {
+                    return error::ReconnectSnafu.fail();
+                };
+                tokio::time::sleep(delay).await;
+
+                match Self::spawn(&self.binary, self.port).await {
+                    Ok(child) => break child,
+                    Err(e) => tracing::warn!("webdriver::supervise | Restart failed: {:?}", e),
+                }
+            };
+            backoff.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn free_port_is_bindable() {
+        let port = pick_free_port().unwrap();
+
+        assert_ne!(port, 0);
+        assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn wait_ready_reads_status() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut strm, _) = listener.accept().await.unwrap();
+            let body = r#"{"value":{"ready":true,"message":""}}"#;
+            let _ = strm.read(&mut [0u8; 1024]).await;
+            strm.write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        });
+
+        wait_ready(&url, Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_ready_gives_up_on_silent_driver() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        // Accept and never answer
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((strm, _)) = listener.accept().await {
+                conns.push(strm);
+            }
+        });
+
+        let started = Instant::now();
+        assert!(wait_ready(&url, Duration::from_millis(500)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn start_missing_binary_fails() {
+        assert!(Driver::start("/nonexistent/geckodriver").await.is_err());
+    }
+}